@@ -17,7 +17,7 @@
 
 extern crate ers;
 
-use ers::Expression;
+use ers::{Expression, Match, Bind};
 
 #[cfg(not(test))]
 fn main() {
@@ -27,19 +27,19 @@ fn main() {
 
     println!("{:?}", expr.replace_all(&pattern, template)); // => ((y z))
 
-    //let t = "a".parse::<Expression>().unwrap();
-    //let p = "x".parse::<Expression>().unwrap();
-
-    //let e = "(x x ((x) x) x)".parse::<Expression>().unwrap();
-    //for sub in e.subexpressions() {
-        //print!("{:?} -> ", sub);
-        //match sub.match_pattern(&p) {
-            //Some(bs) => {
-                //println!("{:?}", t.clone().bind(&bs));
-            //}
-            //None => {
-                //println!("{:?}", sub);
-            //}
-        //}
-    //}
+    let t = "a".parse::<Expression>().unwrap();
+    let p = "x".parse::<Expression>().unwrap();
+
+    let e = "(x x ((x) x) x)".parse::<Expression>().unwrap();
+    for (path, sub) in e.subexpressions() {
+        print!("{:?} {:?} -> ", path, sub);
+        match sub.match_pattern(&p) {
+            Some(bs) => {
+                println!("{:?}", t.clone().bind(&bs));
+            }
+            None => {
+                println!("{:?}", sub);
+            }
+        }
+    }
 }
@@ -0,0 +1,225 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+
+use expression::Expression;
+use number::{BigInt, Number};
+use rule::Rule;
+
+// The built-in rules. Every rule only applies if there is something to
+// evaluate so that rewriting terminates.
+pub fn rules() -> Vec<Rule> {
+    vec![
+        native("(Plus xs___)", |es| combine("Plus", es, Number::Integer(BigInt::zero()), |a, b| a + b)),
+        native("(Times xs___)", times),
+        native("(Minus xs___)", minus),
+        native("(Subtract xs___)", |es| binary(es, |a, b| Some(a - b))),
+        native("(Divide xs___)", |es| binary(es, |a, b| a.checked_div(b))),
+        native("(Power xs___)", |es| binary(es, |a, b| a.pow(b))),
+        native("(Less xs___)", |es| compare(es, |o| o == Ordering::Less)),
+        native("(LessEqual xs___)", |es| compare(es, |o| o != Ordering::Greater)),
+        native("(Greater xs___)", |es| compare(es, |o| o == Ordering::Greater)),
+        native("(GreaterEqual xs___)", |es| compare(es, |o| o != Ordering::Less)),
+        native("(Equal xs___)", |es| compare(es, |o| o == Ordering::Equal)),
+        native("(Unequal xs___)", unequal),
+    ]
+}
+
+fn native<F>(lhs: &str, f: F) -> Rule
+    where F: Fn(&[Expression]) -> Option<Expression> + Send + Sync + 'static {
+    Rule::native(lhs.parse::<Expression>().unwrap(), move |bs| {
+        bs.get("xs").and_then(|b| b.as_sequence()).and_then(|es| f(es))
+    })
+}
+
+fn numbers(es: &[Expression]) -> Option<Vec<Number>> {
+    es.iter().map(Number::from_expression).collect()
+}
+
+// Results which are not finite like `(Divide 1 0.0)` have no numeric leaf,
+// so the expression is left unevaluated.
+fn finite(n: Number) -> Option<Expression> {
+    match n {
+        Number::Real(x) if !x.is_finite() => None,
+        n => Some(n.to_expression())
+    }
+}
+
+fn boolean(b: bool) -> Expression {
+    Expression::Atom(if b { "True" } else { "False" }.to_string())
+}
+
+// Combines the numeric arguments of `Plus` or `Times`. The symbolic
+// arguments follow the result unless it is the identity.
+fn combine<F>(head: &str, es: &[Expression], identity: Number, op: F) -> Option<Expression>
+    where F: Fn(&Number, &Number) -> Number {
+    let mut acc = identity.clone();
+    let mut count = 0;
+    let mut rest: Vec<Expression> = Vec::new();
+    for e in es {
+        match Number::from_expression(e) {
+            Some(n) => {
+                acc = op(&acc, &n);
+                count += 1;
+            }
+            None => rest.push(e.clone())
+        }
+    }
+
+    if rest.is_empty() {
+        return finite(acc);
+    }
+    if count == 0 || (count == 1 && acc != identity) || finite(acc.clone()).is_none() {
+        return None;
+    }
+
+    let mut v = vec![Expression::Atom(head.to_string())];
+    if acc != identity {
+        v.push(acc.to_expression());
+    }
+    v.extend(rest);
+
+    if v.len() == 2 {
+        v.pop()
+    } else {
+        Some(Expression::List(v))
+    }
+}
+
+fn times(es: &[Expression]) -> Option<Expression> {
+    if es.iter().filter_map(Number::from_expression).any(|n| n.is_zero()) {
+        return Some(Number::Integer(BigInt::zero()).to_expression());
+    }
+    combine("Times", es, Number::Integer(BigInt::from_i64(1)), |a, b| a * b)
+}
+
+fn minus(es: &[Expression]) -> Option<Expression> {
+    match es.len() {
+        1 => Number::from_expression(&es[0]).and_then(|n| finite(-&n)),
+        _ => binary(es, |a, b| Some(a - b))
+    }
+}
+
+fn binary<F>(es: &[Expression], op: F) -> Option<Expression>
+    where F: Fn(&Number, &Number) -> Option<Number> {
+    match numbers(es) {
+        Some(ref ns) if ns.len() == 2 => op(&ns[0], &ns[1]).and_then(finite),
+        _ => None
+    }
+}
+
+// Chains the comparison like `a < b < c`.
+fn compare<F>(es: &[Expression], test: F) -> Option<Expression>
+    where F: Fn(Ordering) -> bool {
+    let ns = match numbers(es) {
+        Some(ref ns) if ns.len() >= 2 => ns.clone(),
+        _ => return None
+    };
+
+    let mut res = true;
+    for w in ns.windows(2) {
+        match w[0].partial_cmp(&w[1]) {
+            Some(o) => res = res && test(o),
+            None => return None
+        }
+    }
+    Some(boolean(res))
+}
+
+// All arguments have to be distinct.
+fn unequal(es: &[Expression]) -> Option<Expression> {
+    let ns = match numbers(es) {
+        Some(ref ns) if ns.len() >= 2 => ns.clone(),
+        _ => return None
+    };
+
+    let mut res = true;
+    for i in 0..ns.len() {
+        for j in i + 1..ns.len() {
+            match ns[i].partial_cmp(&ns[j]) {
+                Some(o) => res = res && o != Ordering::Equal,
+                None => return None
+            }
+        }
+    }
+    Some(boolean(res))
+}
+
+#[cfg(test)]
+mod tests {
+    use expression::Expression;
+    use rule::{Rule, RuleSet};
+    use traversal::Traversal;
+
+    fn eval(rules: &RuleSet, e: &str) -> String {
+        let e = e.parse::<Expression>().unwrap();
+        format!("{:?}", e.replace_repeated_rules(rules, 100).unwrap())
+    }
+
+    #[test]
+    fn arithmetic() {
+        let mut rules = RuleSet::new();
+        rules.push_arithmetic();
+
+        assert_eq!(eval(&rules, "(Plus 1 2)"), "3");
+        assert_eq!(eval(&rules, "(Plus 1 a 2 b)"), "(Plus 3 a b)");
+        assert_eq!(eval(&rules, "(Plus 1 a -1)"), "a");
+        assert_eq!(eval(&rules, "(Plus 1/2 1/3)"), "5/6");
+        assert_eq!(eval(&rules, "(Plus 1/4 0.5)"), "0.75");
+        assert_eq!(eval(&rules, "(Times 2 (Plus 1/2 1/2) x)"), "(Times 2 x)");
+        assert_eq!(eval(&rules, "(Times 0 x)"), "0");
+        assert_eq!(eval(&rules, "(Power 2 100)"), "1267650600228229401496703205376");
+        assert_eq!(eval(&rules, "(Power 2 -2)"), "1/4");
+        // results which are too large are not computed
+        assert_eq!(eval(&rules, "(Power 3 4000000000)"), "(Power 3 4000000000)");
+        assert_eq!(eval(&rules, "(Power 1/3 -200000)"), "(Power 1/3 -200000)");
+        assert_eq!(eval(&rules, "(Power 1 4000000000)"), "1");
+        assert_eq!(eval(&rules, "(Power 10 9999)").len(), 10000);
+        assert_eq!(eval(&rules, "(Minus (Subtract 1 3))"), "2");
+        assert_eq!(eval(&rules, "(Divide 6 4)"), "3/2");
+        assert_eq!(eval(&rules, "(Divide 1 0)"), "(Divide 1 0)");
+        // there are no leaves for infinite floats
+        assert_eq!(eval(&rules, "(Divide 1 0.0)"), "(Divide 1 0.0)");
+        assert_eq!(eval(&rules, "(Power 0.0 -1)"), "(Power 0.0 -1)");
+        assert_eq!(eval(&rules, "(Times 1.0e300 1.0e300)"), "(Times 1.0e300 1.0e300)");
+        assert_eq!(eval(&rules, "(Plus 1.0e308 a 1.0e308)"), "(Plus 1.0e308 a 1.0e308)");
+        assert_eq!(eval(&rules, "(Less 1 2 3)"), "True");
+        assert_eq!(eval(&rules, "(GreaterEqual 1 1 2)"), "False");
+        assert_eq!(eval(&rules, "(Unequal 1 2 1)"), "False");
+        assert_eq!(eval(&rules, "(Equal 1/2 0.5)"), "True");
+        assert_eq!(eval(&rules, "(Less 1 a)"), "(Less 1 a)");
+    }
+
+    #[test]
+    fn interleaved() {
+        let mut rules = "((Rule (Fib 0) 0) (Rule (Fib 1) 1) \
+                          (Rule (Fib n_) (Plus (Fib (Plus n -1)) (Fib (Plus n -2)))))"
+            .parse::<RuleSet>().unwrap();
+        rules.push_arithmetic();
+
+        rules.push("(Rule (If True a_ b_) a)".parse::<Rule>().unwrap());
+        rules.push("(Rule (If False a_ b_) b)".parse::<Rule>().unwrap());
+
+        // the arguments have to be evaluated before `(Fib n_)` is applied
+        let eval = |e: &str| {
+            let e = e.parse::<Expression>().unwrap();
+            format!("{:?}", e.replace_with(&rules, Traversal::Innermost, 10000).unwrap())
+        };
+
+        assert_eq!(eval("(Fib 10)"), "55");
+        assert_eq!(eval("(If (Less (Fib 5) 3) small large)"), "large");
+    }
+}
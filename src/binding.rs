@@ -13,19 +13,55 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::borrow::Cow;
 use std::collections::HashMap;
 
-use expression::Expression;
+use expression::{self, Expression};
 
 // TODO: make Binding not clonable
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 /// The `Binding` type.
+///
+/// Bindings usually borrow from the matched expression. Expressions which
+/// are not part of it, like the default value of an optional pattern, are
+/// owned by the binding.
 #[unstable(feature = "ers1")]
 pub enum Binding<'a> {
     /// Binding for a single expression
-    Expression(&'a Expression),
+    Expression(Cow<'a, Expression>),
     /// Binding for a set of zero or more expressions
-    Sequence(&'a[Expression]),
+    Sequence(Cow<'a, [Expression]>),
+}
+
+#[unstable(feature = "ers1")]
+impl<'a> Binding<'a> {
+    /// Returns the bound expression if this is a binding for a single
+    /// expression.
+    #[unstable(feature = "ers1")]
+    pub fn as_expression(&self) -> Option<&Expression> {
+        match *self {
+            Binding::Expression(ref e) => Some(e),
+            Binding::Sequence(_) => None
+        }
+    }
+
+    /// Returns the bound expressions if this is a binding for a sequence.
+    #[unstable(feature = "ers1")]
+    pub fn as_sequence(&self) -> Option<&[Expression]> {
+        match *self {
+            Binding::Expression(_) => None,
+            Binding::Sequence(ref es) => Some(es)
+        }
+    }
+
+    /// Returns a binding which owns the bound expressions.
+    #[unstable(feature = "ers1")]
+    pub fn into_owned(self) -> Binding<'static> {
+        match self {
+            Binding::Expression(e) => Binding::Expression(Cow::Owned(e.into_owned())),
+            Binding::Sequence(es) => Binding::Sequence(Cow::Owned(es.into_owned())),
+        }
+    }
 }
 
 /// The `Bind` interface allows us to bind variables according to the bindings.
@@ -56,23 +92,13 @@ impl Bind for Expression {
         match self {
              Expression::Atom(s) => {
                  match bs.get(&s) {
-                     Some(&Binding::Sequence(seq)) => {
-                         // This should only happen in the root. If this
-                         // shows up deeper in the list we did something
-                         // wrong when iterating through a list.
-
-                         let mut v = vec![
-                            Expression::Atom("Sequence".to_string()),
-                         ];
-                         for s in seq {
-                             v.push(s.clone());
-                         }
-
-                         Expression::List(v)
-
+                     Some(&Binding::Sequence(ref seq)) => {
+                         // spliced by the enclosing list, only remains
+                         // if the template is the variable itself
+                         Expression::Sequence(seq.to_vec())
                      }
-                     Some(&Binding::Expression(e)) => {
-                         e.clone()
+                     Some(&Binding::Expression(ref e)) => {
+                         (**e).clone()
                      }
                      None => {
                          Expression::Atom(s)
@@ -82,6 +108,9 @@ impl Bind for Expression {
              Expression::List(es) => {
                  Expression::List(es.bind(bs))
              }
+             Expression::Sequence(es) => {
+                 Expression::Sequence(es.bind(bs))
+             }
              _ => self
          }
     }
@@ -92,26 +121,8 @@ impl Bind for Vec<Expression> {
         let mut v: Vec<Expression> = Vec::new();
 
         for e in self {
-            // check if a binding could be a sequence as
-            // we need to insert it at this point.
-            // TODO: This match returns true/false and executes a
-            // push if true. Can we do this in the match only?
-            if match e {
-                Expression::Atom(ref s) => {
-                    match bs.get(s) {
-                        Some(&Binding::Sequence(seq)) => {
-                            for s in seq {
-                                v.push(s.clone())
-                            }
-                            false
-                        }
-                        _ => true
-                    }
-                }
-                _ => true
-            } {
-                v.push(e.bind(bs));
-            }
+            // sequences are inserted at this point
+            expression::splice(&mut v, e.bind(bs));
         }
 
         v
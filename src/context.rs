@@ -0,0 +1,127 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+
+use binding::Binding;
+
+/// Attributes change how expressions with a given head are matched.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
+pub enum Attribute {
+    /// The arguments match in any order, e.g. `(Plus x_ 0)` matches
+    /// `(Plus 0 y)` if `Plus` is orderless.
+    Orderless,
+    /// Nested expressions with the same head are equivalent to a single one,
+    /// e.g. `(Plus a_ b_)` matches `(Plus x y z)` binding `b` to `(Plus y z)`
+    /// if `Plus` is flat.
+    Flat,
+}
+
+/// The `Context` holds everything the matcher needs to know beyond the
+/// expression and the pattern, like the pattern tests referenced by name
+/// in patterns such as `x_?positive` or the attributes of heads.
+///
+/// # Example
+///
+/// ```
+/// use ers::{Expression, Match, Binding, Context};
+///
+/// let mut ctx = Context::new();
+/// ctx.add_test("number", |b: &Binding| {
+///     match b.as_expression() {
+///         Some(&Expression::Integer(_)) | Some(&Expression::Float(_)) => true,
+///         _ => false
+///     }
+/// });
+///
+/// let pattern = "(f x_?number)".parse::<Expression>().unwrap();
+///
+/// let expr = "(f 1.5)".parse::<Expression>().unwrap();
+/// assert!(expr.match_pattern_in(&pattern, &ctx).is_some());
+///
+/// let expr = "(f a)".parse::<Expression>().unwrap();
+/// assert!(expr.match_pattern_in(&pattern, &ctx).is_none());
+/// ```
+#[unstable(feature = "ers1")]
+pub struct Context {
+    tests: HashMap<String, Box<Fn(&Binding) -> bool>>,
+    attributes: HashMap<String, Vec<Attribute>>,
+}
+
+#[unstable(feature = "ers1")]
+impl Context {
+    /// Creates an empty context.
+    #[unstable(feature = "ers1")]
+    pub fn new() -> Context {
+        Context {
+            tests: HashMap::new(),
+            attributes: HashMap::new(),
+        }
+    }
+
+    /// Registers a pattern test under the given name. A test replaces any
+    /// previously registered test with the same name.
+    ///
+    /// The test is called with the candidate binding of the pattern it is
+    /// attached to. For sequence patterns like `x__?test` this is the whole
+    /// sequence.
+    #[unstable(feature = "ers1")]
+    pub fn add_test<F>(&mut self, name: &str, f: F) where F: Fn(&Binding) -> bool + 'static {
+        self.tests.insert(name.to_string(), Box::new(f));
+    }
+
+    /// Evaluates the test with the given name. Tests which are not
+    /// registered never succeed.
+    #[unstable(feature = "ers1")]
+    pub fn test(&self, name: &str, b: &Binding) -> bool {
+        match self.tests.get(name) {
+            Some(f) => f(b),
+            None => false
+        }
+    }
+
+    /// Sets an attribute for all expressions with the given head.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ers::{Expression, Match, Context, Attribute};
+    ///
+    /// let mut ctx = Context::new();
+    /// ctx.set_attribute("Plus", Attribute::Orderless);
+    ///
+    /// let expr = "(Plus 0 y)".parse::<Expression>().unwrap();
+    /// let pattern = "(Plus x_ 0)".parse::<Expression>().unwrap();
+    ///
+    /// assert!(expr.match_pattern_in(&pattern, &ctx).is_some());
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn set_attribute(&mut self, head: &str, a: Attribute) {
+        let attributes = self.attributes.entry(head.to_string()).or_insert(Vec::new());
+        if !attributes.contains(&a) {
+            attributes.push(a);
+        }
+    }
+
+    /// Returns true if the attribute is set for the given head.
+    #[unstable(feature = "ers1")]
+    pub fn has_attribute(&self, head: &str, a: Attribute) -> bool {
+        match self.attributes.get(head) {
+            Some(attributes) => attributes.contains(&a),
+            None => false
+        }
+    }
+}
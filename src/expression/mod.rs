@@ -13,38 +13,366 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::error::Error;
 use std::fmt;
+use std::hash::{Hash, Hasher};
 use std::str::FromStr;
+use std::usize;
 
+use binding::{Bind, Binding};
+use context::Context;
+use level::{self, LevelSpec};
 use matching::Match;
-use binding::Bind;
+use number::{BigInt, Number};
+use rule::RuleSet;
+use trace::Derivation;
+use traversal::{self, Rules, Traversal};
+
+pub use self::parser::{Parser, ParserError, ErrorCode, Position};
+pub use self::parser::{excerpt, parse_with_starts};
+pub use self::subexpressions::Subexpressions;
 
 mod parser;
+mod subexpressions;
+
+/// The number of steps after which `replace_repeated` gives up.
+#[unstable(feature = "ers1")]
+pub const REPLACEMENT_LIMIT: usize = 1000;
+
+/// The error returned if repeated rewriting does not terminate within the
+/// step limit.
+#[derive(Clone, Debug)]
+#[unstable(feature = "ers1")]
+pub struct RewriteError {
+    /// The partially rewritten expression after the last step
+    pub expression: Expression,
+    /// The number of steps performed before giving up
+    pub steps: usize,
+}
+
+impl fmt::Display for RewriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "replacement limit reached after {} steps: {:?}", self.steps, self.expression)
+    }
+}
+
+impl Error for RewriteError {}
 
-// TODO: implement equality check
 /// The `Expression` type.
+///
+/// All blanks and patterns carry an optional head. If present only
+/// expressions with that head are matched, i.e. lists whose first element
+/// is a symbol with the given name. The heads `Atom` and `List` are built in
+/// and match any leaf or list respectively. The kind of a leaf is matched by
+/// the heads `Symbol`, `Integer`, `Rational`, `Float` and `String`.
+///
+/// Expressions are compared structurally. They are ordered canonically,
+/// i.e. numbers by value before strings and symbols in alphabetical order
+/// before lists, so that sorting the arguments of a commutative head gives
+/// a normal form.
 #[unstable(feature = "ers1")]
 pub enum Expression {
     /// Contains a boxed slice of `Expressions`
     List(Vec<Expression>),
-    /// Represents a string expression
+    /// Represents a symbol
     Atom(String),
+    /// An integer which fits into 64 bits
+    Integer(i64),
+    /// An integer which does not fit into an `Integer`
+    BigInteger(BigInt),
+    /// A reduced fraction of numerator and denominator where the
+    /// denominator is greater than one
+    Rational(BigInt, BigInt),
+    /// A floating point number, always written with a decimal point
+    Float(f64),
+    /// A string literal written in double quotes. The escapes `\n`, `\t`,
+    /// `\r`, `\0`, `\"`, `\\` and `\u{...}` are supported.
+    String(String),
+    /// A sequence of expressions written `(Sequence ...)` which is spliced
+    /// into the enclosing list when a rule substitutes it. The parser keeps
+    /// a literal sequence within a list as a single element, so that the
+    /// right hand side of `(Rule a (Sequence b c))` stays a sequence.
+    Sequence(Vec<Expression>),
     /// An unnamed pattern matching a single expression
-    Blank,
+    Blank(Option<String>),
     /// An unnamed pattern matching one or more expressions
-    BlankSeq,
+    BlankSeq(Option<String>),
     /// An unnamed pattern matching zero or more expressions
-    BlankNullSeq,
+    BlankNullSeq(Option<String>),
     /// A named pattern matching a single expression
-    Pattern(String),
+    Pattern(String, Option<String>),
     /// A named pattern matching one or more expressions
-    PatternSeq(String),
+    PatternSeq(String, Option<String>),
     /// A named pattern matching zero or more expressions
-    PatternNullSeq(String),
+    PatternNullSeq(String, Option<String>),
+    /// A pattern which only matches if the named test accepts the binding
+    PatternTest(Box<Expression>, String),
+    /// A pattern matching if any of the contained patterns match
+    Alternatives(Vec<Expression>),
+    /// A pattern which may be absent, in which case the default expression
+    /// is matched against it
+    Optional(Box<Expression>, Box<Expression>),
 }
 
 #[unstable(feature = "ers1")]
 impl Expression {
+    /// Returns true if the expression is a bare sequence. This can only
+    /// happen at the top of a result as sequences within lists are spliced.
+    #[unstable(feature = "ers1")]
+    pub fn is_sequence(&self) -> bool {
+        match *self {
+            Expression::Sequence(_) => true,
+            _ => false
+        }
+    }
+
+    /// Returns the elements of a bare sequence or the expression itself as
+    /// the only element otherwise.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::Expression;
+    ///
+    /// let expr = "(f a b)".parse::<Expression>().unwrap();
+    /// let pattern = "(f x__)".parse::<Expression>().unwrap();
+    /// let template = "x".parse::<Expression>().unwrap();
+    ///
+    /// let res = expr.replace(&pattern, template).unwrap();
+    ///
+    /// assert!(res.is_sequence());
+    /// assert_eq!(res.into_sequence().len(), 2);
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn into_sequence(self) -> Vec<Expression> {
+        match self {
+            Expression::Sequence(es) => es,
+            e => vec![e]
+        }
+    }
+
+    /// Returns an iterator over the expression and all its subexpressions
+    /// in pre-order, i.e. every list comes before its elements. Each
+    /// subexpression is paired with its position.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::Expression;
+    ///
+    /// let expr = "(f (g a) b)".parse::<Expression>().unwrap();
+    ///
+    /// for (path, e) in expr.subexpressions() {
+    ///     // [] (f (g a) b), [0] f, [1] (g a), [1, 0] g, [1, 1] a, [2] b
+    /// }
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn subexpressions(&self) -> Subexpressions {
+        subexpressions::subexpressions(self, false)
+    }
+
+    /// Same as `subexpressions` but in post-order, i.e. every list comes
+    /// after its elements.
+    #[unstable(feature = "ers1")]
+    pub fn subexpressions_post_order(&self) -> Subexpressions {
+        subexpressions::subexpressions(self, true)
+    }
+
+    /// Returns the subexpression at the position or `None` if there is no
+    /// such subexpression.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::Expression;
+    ///
+    /// let expr = "(f (g a) b)".parse::<Expression>().unwrap();
+    ///
+    /// expr.get(&[1, 1]); // => Some(a)
+    /// expr.get(&[2, 0]); // => None
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn get(&self, path: &[usize]) -> Option<&Expression> {
+        let mut e = self;
+        for &i in path {
+            e = match e.children().get(i) {
+                Some(c) => c,
+                None => return None
+            };
+        }
+        Some(e)
+    }
+
+    /// Same as `get` but returns the subexpression mutably.
+    #[unstable(feature = "ers1")]
+    pub fn get_mut(&mut self, path: &[usize]) -> Option<&mut Expression> {
+        let mut e = self;
+        for &i in path {
+            e = match e.children_mut().and_then(|es| es.get_mut(i)) {
+                Some(c) => c,
+                None => return None
+            };
+        }
+        Some(e)
+    }
+
+    /// Returns the expression with the subexpression at the position
+    /// replaced or `None` if there is no such subexpression. A sequence is
+    /// spliced into the enclosing list.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::Expression;
+    ///
+    /// let expr = "(f (g a) b)".parse::<Expression>().unwrap();
+    /// let c = "c".parse::<Expression>().unwrap();
+    ///
+    /// expr.replace_at(&[1, 1], c); // => Some((f (g c) b))
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn replace_at(&self, path: &[usize], e: Expression) -> Option<Expression> {
+        let (i, parent) = match path.split_last() {
+            Some((&i, parent)) => (i, parent),
+            None => return Some(e)
+        };
+
+        let mut res = self.clone();
+        {
+            let es = match res.get_mut(parent).and_then(|p| p.children_mut()) {
+                Some(es) => es,
+                None => return None
+            };
+            if i >= es.len() {
+                return None;
+            }
+
+            let rest = es.split_off(i + 1);
+            es.pop();
+            splice(es, e);
+            es.extend(rest);
+        }
+        Some(res)
+    }
+
+    // the elements of lists and sequences
+    fn children(&self) -> &[Expression] {
+        match *self {
+            Expression::List(ref es) | Expression::Sequence(ref es) => es,
+            _ => &[]
+        }
+    }
+
+    fn children_mut(&mut self) -> Option<&mut Vec<Expression>> {
+        match *self {
+            Expression::List(ref mut es) | Expression::Sequence(ref mut es) => Some(es),
+            _ => None
+        }
+    }
+
+    /// Returns the positions of all subexpressions in the levels which match
+    /// the pattern. Elements come before the lists containing them.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, LevelSpec};
+    ///
+    /// let expr = "(f (f a) b)".parse::<Expression>().unwrap();
+    /// let pattern = "(f _)".parse::<Expression>().unwrap();
+    ///
+    /// expr.positions(&pattern, LevelSpec::all()); // => [[1]]
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn positions(&self, p: &Expression, levels: LevelSpec) -> Vec<Vec<usize>> {
+        let mut res = Vec::new();
+        self.walk_matches(p, levels, None, |path, _, _| res.push(path.to_vec()));
+        res
+    }
+
+    /// Same as `positions` but matches in the given context, like rewriting
+    /// with a `RuleSet` does.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Attribute, Context, Expression, LevelSpec};
+    ///
+    /// let mut ctx = Context::new();
+    /// ctx.set_attribute("Plus", Attribute::Orderless);
+    ///
+    /// let expr = "(f (Plus a 1) (Plus 2 b))".parse::<Expression>().unwrap();
+    /// let pattern = "(Plus x_Integer y_)".parse::<Expression>().unwrap();
+    ///
+    /// assert_eq!(expr.positions_in(&pattern, LevelSpec::all(), &ctx), vec![vec![1], vec![2]]);
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn positions_in(&self, p: &Expression, levels: LevelSpec,
+                        ctx: &Context) -> Vec<Vec<usize>> {
+        let mut res = Vec::new();
+        self.walk_matches(p, levels, Some(ctx), |path, _, _| res.push(path.to_vec()));
+        res
+    }
+
+    /// Returns all subexpressions in the levels which match the pattern
+    /// together with their bindings, in the same order as `positions`.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, LevelSpec};
+    ///
+    /// let expr = "(f (g 1) (g x) 2)".parse::<Expression>().unwrap();
+    /// let pattern = "(g x_Integer)".parse::<Expression>().unwrap();
+    ///
+    /// expr.cases(&pattern, LevelSpec::up_to(1)); // => [((g 1), {"x": 1})]
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn cases<'a>(&'a self, p: &Expression, levels: LevelSpec)
+                     -> Vec<(&'a Expression, HashMap<String, Binding<'a>>)> {
+        let mut res = Vec::new();
+        self.walk_matches(p, levels, None, |_, e, bs| res.push((e, bs)));
+        res
+    }
+
+    /// Same as `cases` but matches in the given context.
+    #[unstable(feature = "ers1")]
+    pub fn cases_in<'a>(&'a self, p: &Expression, levels: LevelSpec, ctx: &Context)
+                        -> Vec<(&'a Expression, HashMap<String, Binding<'a>>)> {
+        let mut res = Vec::new();
+        self.walk_matches(p, levels, Some(ctx), |_, e, bs| res.push((e, bs)));
+        res
+    }
+
+    /// Returns the number of subexpressions in the levels which match the
+    /// pattern.
+    #[unstable(feature = "ers1")]
+    pub fn count(&self, p: &Expression, levels: LevelSpec) -> usize {
+        let mut n = 0;
+        self.walk_matches(p, levels, None, |_, _, _| n += 1);
+        n
+    }
+
+    /// Same as `count` but matches in the given context.
+    #[unstable(feature = "ers1")]
+    pub fn count_in(&self, p: &Expression, levels: LevelSpec, ctx: &Context) -> usize {
+        let mut n = 0;
+        self.walk_matches(p, levels, Some(ctx), |_, _, _| n += 1);
+        n
+    }
+
+    // Calls `f` with the position, the subexpression and the bindings of
+    // every subexpression in the levels matching the pattern. Without a
+    // context the pattern is matched like in an empty one.
+    fn walk_matches<'a, F>(&'a self, p: &Expression, levels: LevelSpec, ctx: Option<&Context>,
+                           mut f: F)
+        where F: FnMut(&[usize], &'a Expression, HashMap<String, Binding<'a>>) {
+        level::walk(self, levels, |path, e| {
+            let bs = match ctx {
+                Some(ctx) => e.match_pattern_in(p, ctx),
+                None => e.match_pattern(p)
+            };
+            if let Some(bs) = bs {
+                f(path, e, bs);
+            }
+        });
+    }
+
     /// Matches the `Expression` with a pattern and if successful binds returns
     /// the bound template.
     ///
@@ -60,7 +388,7 @@ impl Expression {
     /// ```
     #[unstable(feature = "ers1")]
     pub fn replace(&self, pattern: &Expression, template: Expression) -> Option<Expression> {
-        self.match_pattern(pattern).map(move |bs| template.bind(&bs))
+        self.match_pattern(pattern).map(|bs| template.bind(&bs))
     }
 
     /// Replaces all expressions and subexpressions with the provided pattern
@@ -78,14 +406,40 @@ impl Expression {
     /// ```
     #[unstable(feature = "ers1")]
     pub fn replace_all(&self, pattern: &Expression, template: Expression) -> Expression {
-        let (e, _) = self.replace_rec(pattern, template);
-        e
+        let rules = Rules::Pattern(pattern, &template);
+        match traversal::rewrite(self, rules, Traversal::ParallelOutermost, usize::MAX) {
+            Ok(e) => e,
+            Err(err) => err.expression
+        }
+    }
+
+    /// Replaces all expressions and subexpressions matching any of the rules
+    /// in a single traversal. At every subexpression the rules are tried in
+    /// order and the first matching one is applied. Subexpressions of
+    /// replaced expressions are not visited.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet};
+    ///
+    /// let expr = "((x r) (z s))".parse::<Expression>().unwrap();
+    /// let rules = "((Rule (x a_) (y a)) (Rule (z a_) (w a)))".parse::<RuleSet>().unwrap();
+    ///
+    /// expr.replace_all_rules(&rules); // => ((y r) (w s))
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn replace_all_rules(&self, rules: &RuleSet) -> Expression {
+        match traversal::rewrite(self, Rules::Set(rules), Traversal::ParallelOutermost, usize::MAX) {
+            Ok(e) => e,
+            Err(err) => err.expression
+        }
     }
 
     /// Replaces all expressions and subexpression repeatedly until the
     /// expression does not change anymore.
-    /// The hardcoded limit is 1000 repetitions. The function panics if the
-    /// limit is reached.
+    /// The limit is `REPLACEMENT_LIMIT` repetitions. The function panics if
+    /// the limit is reached, see `try_replace_repeated` for a fallible
+    /// variant.
     ///
     /// # Example
     /// ```
@@ -99,42 +453,168 @@ impl Expression {
     /// ```
     #[unstable(feature = "experimental")]
     pub fn replace_repeated(&self, pattern: &Expression, template: Expression) -> Expression {
-        // TODO: set limit as global constant
-        let mut limit = 1000;
-        let mut expr = self.clone();
-        while limit >= 0 {
-            limit -= 1;
-            let (new_expr, replaced) = expr.replace_rec(pattern, template.clone());
-            if !replaced {
-                return new_expr;
-            }
-            expr = new_expr;
-        }
-        // TODO: panic might not be the right thing to do
-        panic!("replacement limit reached!");
-    }
-
-    fn replace_rec(&self, pattern: &Expression, template: Expression) -> (Expression, bool) {
-        match self.match_pattern(pattern) {
-            None => {
-                match self {
-                    &Expression::List(ref es) => {
-                        let mut v: Vec<Expression> = Vec::new();
-                        let mut replaced = false;
-                        for e in es {
-                            let (new_e, r) = e.replace_rec(pattern, template.clone());
-                            if r {
-                                replaced = true;
-                            }
-                            v.push(new_e);
-                        }
-                        (Expression::List(v), replaced)
-                    }
-                    _ => (self.clone(), false) // not replaced
-                }
+        match self.try_replace_repeated(pattern, template, REPLACEMENT_LIMIT) {
+            Ok(e) => e,
+            Err(_) => panic!("replacement limit reached!")
+        }
+    }
+
+    /// Replaces all expressions and subexpression repeatedly until the
+    /// expression does not change anymore or `limit` steps were performed.
+    /// In the latter case the error contains the expression after the last
+    /// step.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::Expression;
+    ///
+    /// let expr = "(x z)".parse::<Expression>().unwrap();
+    /// let pattern = "(x a_)".parse::<Expression>().unwrap();
+    /// let template = "(x (x a))".parse::<Expression>().unwrap();
+    ///
+    /// let err = expr.try_replace_repeated(&pattern, template, 2).unwrap_err();
+    ///
+    /// assert_eq!(err.steps, 2);
+    /// assert_eq!(format!("{:?}", err.expression), "(x (x (x z)))");
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn try_replace_repeated(&self, pattern: &Expression, template: Expression,
+                                limit: usize) -> Result<Expression, RewriteError> {
+        traversal::rewrite_repeated(self, Rules::Pattern(pattern, &template), limit)
+    }
+
+    /// Applies `replace_all_rules` repeatedly until the expression does not
+    /// change anymore or `limit` steps were performed. In the latter case
+    /// the error contains the expression after the last step.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet, REPLACEMENT_LIMIT};
+    ///
+    /// let expr = "(x (z (x r)))".parse::<Expression>().unwrap();
+    /// let rules = "((Rule (x a_) (y a)) (Rule (z a_) (w a)))".parse::<RuleSet>().unwrap();
+    ///
+    /// expr.replace_repeated_rules(&rules, REPLACEMENT_LIMIT); // => Ok((y (w (y r))))
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn replace_repeated_rules(&self, rules: &RuleSet, limit: usize) -> Result<Expression, RewriteError> {
+        traversal::rewrite_repeated(self, Rules::Set(rules), limit)
+    }
+
+    /// Same as `replace_repeated_rules` but records every applied rule like
+    /// `trace_with`.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet};
+    ///
+    /// let expr = "(x (z r))".parse::<Expression>().unwrap();
+    /// let rules = "((Rule (x a_) (y a)) (Rule (z a_) (w a)))".parse::<RuleSet>().unwrap();
+    ///
+    /// let derivation = expr.trace_repeated_rules(&rules, 10);
+    ///
+    /// assert_eq!(format!("{}", derivation), "\
+    /// (x (z r))
+    ///   = (y (z r))  by rule 0 (x a_) at [] with a = (z r)
+    ///   = (y (w r))  by rule 1 (z a_) at [1] with a = r
+    /// ");
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn trace_repeated_rules(&self, rules: &RuleSet, limit: usize) -> Derivation {
+        traversal::trace_repeated(self, Rules::Set(rules), limit)
+    }
+
+    /// Rewrites the expression with the rules according to the traversal.
+    /// Fails if more than `limit` rules would have to be applied, in which
+    /// case the error contains the expression rewritten so far.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet, Traversal};
+    ///
+    /// let expr = "(f (f a))".parse::<Expression>().unwrap();
+    /// let rules = "(Rule (f x_) (g x))".parse::<RuleSet>().unwrap();
+    ///
+    /// expr.replace_with(&rules, Traversal::OnceAnywhere, 10); // => Ok((g (f a)))
+    /// expr.replace_with(&rules, Traversal::BottomUp, 10); // => Ok((g (g a)))
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn replace_with(&self, rules: &RuleSet, traversal: Traversal,
+                        limit: usize) -> Result<Expression, RewriteError> {
+        traversal::rewrite(self, Rules::Set(rules), traversal, limit)
+    }
+
+    /// Same as `replace_with` but records every applied rule, where it was
+    /// applied and the bindings of the match. Useful to find out how a
+    /// surprising result came about. `replace_all_rules` performs the same
+    /// steps as `Traversal::ParallelOutermost`.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet, Traversal};
+    ///
+    /// let expr = "(f (f a))".parse::<Expression>().unwrap();
+    /// let rules = "(Rule (f x_) (g x))".parse::<RuleSet>().unwrap();
+    ///
+    /// let derivation = expr.trace_with(&rules, Traversal::TopDown, 10);
+    ///
+    /// for step in derivation.steps.iter() {
+    ///     println!("{}", step); // => (f (f a)) -> (g (f a)) by rule 0 (f x_) at [] with x = (f a)
+    /// }
+    /// println!("{}", derivation);
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn trace_with(&self, rules: &RuleSet, traversal: Traversal, limit: usize) -> Derivation {
+        traversal::trace(self, Rules::Set(rules), traversal, limit)
+    }
+
+    /// Applies the rules once to every subexpression in the levels. Unlike
+    /// `replace_all_rules` deeper subexpressions are rewritten first and the
+    /// rules are then applied to the lists containing the results.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet, LevelSpec};
+    ///
+    /// let expr = "(f a (g a))".parse::<Expression>().unwrap();
+    /// let rules = "(Rule a b)".parse::<RuleSet>().unwrap();
+    ///
+    /// expr.replace_at_levels(&rules, LevelSpec::exactly(1)); // => (f b (g a))
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn replace_at_levels(&self, rules: &RuleSet, levels: LevelSpec) -> Expression {
+        level::replace_at_levels(self, rules, levels)
+    }
+
+    /// Applies the rules once to the subexpressions at the positions, e.g.
+    /// the ones returned by `positions`. Subexpressions at invalid positions
+    /// or which no rule matches are left unchanged.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::{Expression, RuleSet};
+    ///
+    /// let expr = "(f a (g a))".parse::<Expression>().unwrap();
+    /// let rules = "(Rule a b)".parse::<RuleSet>().unwrap();
+    ///
+    /// expr.replace_at_positions(&rules, &[vec![2, 1]]); // => (f a (g b))
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn replace_at_positions(&self, rules: &RuleSet, paths: &[Vec<usize>]) -> Expression {
+        level::replace_at_positions(self, rules, paths)
+    }
+}
+
+// Appends the expression to the list, the elements of a sequence are
+// appended individually.
+pub fn splice(v: &mut Vec<Expression>, e: Expression) {
+    match e {
+        Expression::Sequence(es) => {
+            for e in es {
+                splice(v, e);
             }
-            Some(bs) => (template.bind(&bs), true) // replaced
         }
+        e => v.push(e)
     }
 }
 
@@ -151,32 +631,236 @@ impl Clone for Expression {
             &Expression::Atom(ref s) => {
                 Expression::Atom(s.clone())
             }
-            &Expression::Blank => {
-                Expression::Blank
+            &Expression::Integer(n) => {
+                Expression::Integer(n)
+            }
+            &Expression::BigInteger(ref n) => {
+                Expression::BigInteger(n.clone())
+            }
+            &Expression::Rational(ref n, ref d) => {
+                Expression::Rational(n.clone(), d.clone())
+            }
+            &Expression::Float(x) => {
+                Expression::Float(x)
+            }
+            &Expression::String(ref s) => {
+                Expression::String(s.clone())
             }
-            &Expression::BlankSeq => {
-                Expression::BlankSeq
+            &Expression::Sequence(ref es) => {
+                Expression::Sequence(es.clone())
             }
-            &Expression::BlankNullSeq => {
-                Expression::BlankNullSeq
+            &Expression::Blank(ref h) => {
+                Expression::Blank(h.clone())
             }
-            &Expression::Pattern(ref s) => {
-                Expression::Pattern(s.clone())
+            &Expression::BlankSeq(ref h) => {
+                Expression::BlankSeq(h.clone())
             }
-            &Expression::PatternSeq(ref s) => {
-                Expression::PatternSeq(s.clone())
+            &Expression::BlankNullSeq(ref h) => {
+                Expression::BlankNullSeq(h.clone())
             }
-            &Expression::PatternNullSeq(ref s) => {
-                Expression::PatternNullSeq(s.clone())
+            &Expression::Pattern(ref s, ref h) => {
+                Expression::Pattern(s.clone(), h.clone())
             }
+            &Expression::PatternSeq(ref s, ref h) => {
+                Expression::PatternSeq(s.clone(), h.clone())
+            }
+            &Expression::PatternNullSeq(ref s, ref h) => {
+                Expression::PatternNullSeq(s.clone(), h.clone())
+            }
+            &Expression::PatternTest(ref p, ref t) => {
+                Expression::PatternTest(p.clone(), t.clone())
+            }
+            &Expression::Alternatives(ref ps) => {
+                Expression::Alternatives(ps.clone())
+            }
+            &Expression::Optional(ref p, ref d) => {
+                Expression::Optional(p.clone(), d.clone())
+            }
+        }
+    }
+}
+
+// Equality is structural. Floats are equal if their bits are equal which
+// makes the equality reflexive even for NaN.
+impl PartialEq for Expression {
+    fn eq(&self, other: &Expression) -> bool {
+        match (self, other) {
+            (&Expression::List(ref xs), &Expression::List(ref ys)) => xs == ys,
+            (&Expression::Sequence(ref xs), &Expression::Sequence(ref ys)) => xs == ys,
+            (&Expression::Atom(ref x), &Expression::Atom(ref y)) => x == y,
+            (&Expression::Integer(x), &Expression::Integer(y)) => x == y,
+            (&Expression::BigInteger(ref x), &Expression::BigInteger(ref y)) => x == y,
+            (&Expression::Rational(ref x, ref a), &Expression::Rational(ref y, ref b)) => x == y && a == b,
+            (&Expression::Float(x), &Expression::Float(y)) => x.to_bits() == y.to_bits(),
+            (&Expression::String(ref x), &Expression::String(ref y)) => x == y,
+            (&Expression::Blank(ref h), &Expression::Blank(ref k)) => h == k,
+            (&Expression::BlankSeq(ref h), &Expression::BlankSeq(ref k)) => h == k,
+            (&Expression::BlankNullSeq(ref h), &Expression::BlankNullSeq(ref k)) => h == k,
+            (&Expression::Pattern(ref x, ref h), &Expression::Pattern(ref y, ref k)) => x == y && h == k,
+            (&Expression::PatternSeq(ref x, ref h), &Expression::PatternSeq(ref y, ref k)) => x == y && h == k,
+            (&Expression::PatternNullSeq(ref x, ref h), &Expression::PatternNullSeq(ref y, ref k)) => {
+                x == y && h == k
+            }
+            (&Expression::PatternTest(ref p, ref t), &Expression::PatternTest(ref q, ref u)) => {
+                t == u && p == q
+            }
+            (&Expression::Alternatives(ref xs), &Expression::Alternatives(ref ys)) => xs == ys,
+            (&Expression::Optional(ref p, ref d), &Expression::Optional(ref q, ref e)) => {
+                p == q && d == e
+            }
+            _ => false
         }
     }
 }
 
+impl Eq for Expression {}
+
+impl Hash for Expression {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        kind(self).hash(state);
+        match *self {
+            Expression::List(ref es) |
+            Expression::Sequence(ref es) |
+            Expression::Alternatives(ref es) => es.hash(state),
+            Expression::Atom(ref s) | Expression::String(ref s) => s.hash(state),
+            Expression::Integer(n) => n.hash(state),
+            Expression::BigInteger(ref n) => n.hash(state),
+            Expression::Rational(ref n, ref d) => {
+                n.hash(state);
+                d.hash(state);
+            }
+            Expression::Float(x) => x.to_bits().hash(state),
+            Expression::Blank(ref h) |
+            Expression::BlankSeq(ref h) |
+            Expression::BlankNullSeq(ref h) => h.hash(state),
+            Expression::Pattern(ref s, ref h) |
+            Expression::PatternSeq(ref s, ref h) |
+            Expression::PatternNullSeq(ref s, ref h) => {
+                s.hash(state);
+                h.hash(state);
+            }
+            Expression::PatternTest(ref p, ref t) => {
+                p.hash(state);
+                t.hash(state);
+            }
+            Expression::Optional(ref p, ref d) => {
+                p.hash(state);
+                d.hash(state);
+            }
+        }
+    }
+}
+
+// The canonical order. Numbers come first ordered by their value, followed
+// by strings and symbols in alphabetical order and lists. Lists are ordered
+// by their length and then element by element. Patterns come last.
+impl Ord for Expression {
+    fn cmp(&self, other: &Expression) -> Ordering {
+        let (k, l) = (kind(self), kind(other));
+        if k != l {
+            return k.cmp(&l);
+        }
+
+        match (self, other) {
+            (&Expression::Atom(ref x), &Expression::Atom(ref y)) |
+            (&Expression::String(ref x), &Expression::String(ref y)) => {
+                // alphabetically with lower case letters first
+                x.to_lowercase().cmp(&y.to_lowercase()).then_with(|| y.cmp(x))
+            }
+            (&Expression::List(ref xs), &Expression::List(ref ys)) |
+            (&Expression::Sequence(ref xs), &Expression::Sequence(ref ys)) |
+            (&Expression::Alternatives(ref xs), &Expression::Alternatives(ref ys)) => {
+                xs.len().cmp(&ys.len()).then_with(|| xs.cmp(ys))
+            }
+            (&Expression::Blank(ref h), &Expression::Blank(ref k)) |
+            (&Expression::BlankSeq(ref h), &Expression::BlankSeq(ref k)) |
+            (&Expression::BlankNullSeq(ref h), &Expression::BlankNullSeq(ref k)) => h.cmp(k),
+            (&Expression::Pattern(ref x, ref h), &Expression::Pattern(ref y, ref k)) |
+            (&Expression::PatternSeq(ref x, ref h), &Expression::PatternSeq(ref y, ref k)) |
+            (&Expression::PatternNullSeq(ref x, ref h), &Expression::PatternNullSeq(ref y, ref k)) => {
+                (x, h).cmp(&(y, k))
+            }
+            (&Expression::PatternTest(ref p, ref t), &Expression::PatternTest(ref q, ref u)) => {
+                (p, t).cmp(&(q, u))
+            }
+            (&Expression::Optional(ref p, ref d), &Expression::Optional(ref q, ref e)) => {
+                (p, d).cmp(&(q, e))
+            }
+            _ => compare_numbers(self, other)
+        }
+    }
+}
+
+impl PartialOrd for Expression {
+    fn partial_cmp(&self, other: &Expression) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// The rank of the variant in the canonical order, all numbers share one.
+fn kind(e: &Expression) -> u8 {
+    match *e {
+        Expression::Integer(_) |
+        Expression::BigInteger(_) |
+        Expression::Rational(..) |
+        Expression::Float(_) => 0,
+        Expression::String(_) => 1,
+        Expression::Atom(_) => 2,
+        Expression::List(_) => 3,
+        Expression::Sequence(_) => 4,
+        Expression::Blank(_) => 5,
+        Expression::BlankSeq(_) => 6,
+        Expression::BlankNullSeq(_) => 7,
+        Expression::Pattern(..) => 8,
+        Expression::PatternSeq(..) => 9,
+        Expression::PatternNullSeq(..) => 10,
+        Expression::PatternTest(..) => 11,
+        Expression::Alternatives(_) => 12,
+        Expression::Optional(..) => 13,
+    }
+}
+
+// Numbers are ordered by value. Equal exact numbers come before floats,
+// NaN comes last. Ties between different representations of the same value
+// are broken by the variant so that the order is consistent with `==`.
+fn compare_numbers(a: &Expression, b: &Expression) -> Ordering {
+    let (x, y) = (Number::from_expression(a).unwrap(), Number::from_expression(b).unwrap());
+    match x.partial_cmp(&y) {
+        Some(Ordering::Equal) => {}
+        Some(o) => { return o; }
+        None => {
+            let (p, q) = (x.to_f64().is_nan(), y.to_f64().is_nan());
+            if p != q {
+                return p.cmp(&q);
+            }
+        }
+    }
+
+    match (a, b) {
+        (&Expression::Float(x), &Expression::Float(y)) => x.to_bits().cmp(&y.to_bits()),
+        (&Expression::Float(_), _) => Ordering::Greater,
+        (_, &Expression::Float(_)) => Ordering::Less,
+        (&Expression::Rational(ref n, ref d), &Expression::Rational(ref m, ref e)) => {
+            (n, d).cmp(&(m, e))
+        }
+        (&Expression::Integer(_), &Expression::Integer(_)) => Ordering::Equal,
+        (&Expression::Integer(_), _) => Ordering::Less,
+        (_, &Expression::Integer(_)) => Ordering::Greater,
+        (&Expression::BigInteger(_), &Expression::BigInteger(_)) => Ordering::Equal,
+        (&Expression::BigInteger(_), _) => Ordering::Less,
+        _ => Ordering::Greater
+    }
+}
+
 impl fmt::Debug for Expression {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Expression::Atom(ref s) => { write!(f, "{}", s) }
+            Expression::Integer(n) => { write!(f, "{}", n) }
+            Expression::BigInteger(ref n) => { write!(f, "{}", n) }
+            Expression::Rational(ref n, ref d) => { write!(f, "{}/{}", n, d) }
+            Expression::Float(x) => { write!(f, "{}", Number::Real(x)) }
+            Expression::String(ref s) => { write!(f, "\"{}\"", escape(s)) }
             Expression::List(ref es) => {
                 let mut s = String::new();
                 for i in (0..es.len()) {
@@ -188,28 +872,90 @@ impl fmt::Debug for Expression {
                 }
                 write!(f, "({})", s)
             }
-            Expression::Blank => { write!(f, "_") }
-            Expression::BlankSeq => { write!(f, "__") }
-            Expression::BlankNullSeq => { write!(f, "___") }
-            Expression::Pattern(ref s) => { write!(f, "{}_", s)}
-            Expression::PatternSeq(ref s) => { write!(f, "{}__", s)}
-            Expression::PatternNullSeq(ref s) => { write!(f, "{}___", s)}
+            Expression::Sequence(ref es) => {
+                let mut s = String::from("Sequence");
+                for e in es {
+                    s = s + &format!(" {:?}", e)[..];
+                }
+                write!(f, "({})", s)
+            }
+            Expression::Blank(ref h) => { write!(f, "_{}", head(h)) }
+            Expression::BlankSeq(ref h) => { write!(f, "__{}", head(h)) }
+            Expression::BlankNullSeq(ref h) => { write!(f, "___{}", head(h)) }
+            Expression::Pattern(ref s, ref h) => { write!(f, "{}_{}", s, head(h))}
+            Expression::PatternSeq(ref s, ref h) => { write!(f, "{}__{}", s, head(h))}
+            Expression::PatternNullSeq(ref s, ref h) => { write!(f, "{}___{}", s, head(h))}
+            Expression::PatternTest(ref p, ref t) => { write!(f, "{:?}?{}", p, t)}
+            Expression::Alternatives(ref ps) => {
+                let mut s = String::new();
+                for i in (0..ps.len()) {
+                    let f = format!("{:?}", ps[i]);
+                    s = s + &f[..];
+                    if i != ps.len() - 1 {
+                        s.push_str(" | ");
+                    }
+                }
+                write!(f, "{}", s)
+            }
+            Expression::Optional(ref p, ref d) => { write!(f, "{:?}:{:?}", p, d)}
         }
     }
 }
 
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+// the contents of a string literal as it is written between the quotes
+fn escape(s: &str) -> String {
+    let mut res = String::new();
+    for c in s.chars() {
+        match c {
+            '"' => res.push_str("\\\""),
+            '\\' => res.push_str("\\\\"),
+            '\n' => res.push_str("\\n"),
+            '\t' => res.push_str("\\t"),
+            '\r' => res.push_str("\\r"),
+            '\0' => res.push_str("\\0"),
+            c if c.is_control() => res.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => res.push(c)
+        }
+    }
+    res
+}
+
+// the head of a blank or pattern as it is written after the underscores
+fn head(h: &Option<String>) -> &str {
+    match *h {
+        Some(ref h) => &h[..],
+        None => ""
+    }
+}
+
 impl FromStr for Expression {
-    type Err = parser::ParserError;
-    fn from_str(s: &str) -> Result<Expression, parser::ParserError> {
-        let mut parser = parser::Parser::new(s.chars());
+    type Err = ParserError;
+    fn from_str(s: &str) -> Result<Expression, ParserError> {
+        let mut parser = Parser::new(s.chars());
 
-        parser.parse()
+        let e = try!{ parser.parse() };
+        try!{ parser.end() };
+        Ok(e)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Expression;
+    use std::collections::HashSet;
+
+    use std::cmp::Ordering;
+    use number::BigInt;
+    use super::{Expression, Parser, ParserError, ErrorCode, Position};
+    use binding::Binding;
+    use context::{Attribute, Context};
+    use level::LevelSpec;
+    use rule::RuleSet;
 
     #[test]
     fn debug() {
@@ -231,6 +977,341 @@ mod tests {
         assert_eq!(format!("{:?}", expr.unwrap()), "(a b (c d))");
     }
 
+    #[test]
+    fn parse_head() {
+        for p in &["_", "__", "___", "x_", "x__", "x___",
+                   "_f", "__f", "___f", "x_f", "x__f", "x___f"] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+
+        assert!("x_f_".parse::<Expression>().is_err());
+        assert!("x____".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn parse_pattern_test() {
+        for p in &["x_?t", "x__f?t", "_?t", "(f x_)?t", "(f x_?t)", "x_?t?u"] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+
+        assert!("x_?".parse::<Expression>().is_err());
+        assert!("x_? y".parse::<Expression>().is_err());
+
+        // '?' is part of ordinary symbols
+        for p in &["EvenQ?", "a?b", "(f ok? x_?EvenQ)"] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+        match "(f a?b)".parse::<Expression>().unwrap() {
+            Expression::List(ref es) => assert_eq!(es[1], Expression::Atom("a?b".to_string())),
+            e => panic!("not a list: {:?}", e)
+        }
+    }
+
+    #[test]
+    fn parse_alternatives() {
+        for p in &["a | b", "(Sin x_) | (Cos x_)", "(f a | b c)", "(f x_?t | y__ | _g)"] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+
+        let expr = "(f x_|y_  |  c)".parse::<Expression>();
+        assert_eq!(format!("{:?}", expr.unwrap()), "(f x_ | y_ | c)");
+
+        // '|' is part of ordinary symbols
+        let expr = "(f a|b | c)".parse::<Expression>().unwrap();
+        match expr {
+            Expression::List(ref es) => {
+                assert_eq!(es[1], Expression::Alternatives(vec![Expression::Atom("a|b".to_string()),
+                                                                Expression::Atom("c".to_string())]));
+            }
+            e => panic!("not a list: {:?}", e)
+        }
+
+        assert!("(f a |)".parse::<Expression>().is_err());
+        assert!("a |".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn parse_leaves() {
+        for p in &["(f x 1 -2 3/4 0.5 \"a b\")", "12345678901234567890", "-1/2", "2.5e-8"] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+
+        match "(1 x 1.0 \"1\" 2/4)".parse::<Expression>().unwrap() {
+            Expression::List(ref es) => {
+                match (&es[0], &es[1], &es[2], &es[3], &es[4]) {
+                    (&Expression::Integer(1), &Expression::Atom(_), &Expression::Float(_),
+                     &Expression::String(_), &Expression::Atom(_)) => {}
+                    _ => panic!("wrong leaf types: {:?}", es)
+                }
+            }
+            _ => panic!("not a list")
+        }
+
+        match "12345678901234567890".parse::<Expression>().unwrap() {
+            Expression::BigInteger(_) => {}
+            e => panic!("not a big integer: {:?}", e)
+        }
+
+        assert!("(f \"a)".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn parse_string() {
+        for p in &[r#""a \"b\" (c)""#, r#""\\n\n\t\r\0""#, r#""\u{7f}""#, r#""""#] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+
+        match r#""x\u{1F600}\u{e9}\\""#.parse::<Expression>().unwrap() {
+            Expression::String(ref s) => assert_eq!(s, "x\u{1F600}\u{e9}\\"),
+            e => panic!("not a string: {:?}", e)
+        }
+
+        assert_eq!(r#""a\q""#.parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::InvalidEscape, 3, 1, 4));
+        assert_eq!(r#""\u{110000}""#.parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::InvalidEscape, 11, 1, 12));
+        assert_eq!(r#""\u{41""#.parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::InvalidEscape, 6, 1, 7));
+        assert_eq!(r#"("\u{41" b) }"#.parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::InvalidEscape, 7, 1, 8));
+        assert_eq!(r#""\u{41"#.parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::UnterminatedString, 0, 1, 1));
+        assert_eq!(r#""a\""#.parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::UnterminatedString, 0, 1, 1));
+    }
+
+    fn error(code: ErrorCode, offset: usize, line: usize, column: usize) -> ParserError {
+        ParserError::SyntaxError(code, Position { offset: offset, line: line, column: column })
+    }
+
+    #[test]
+    fn parse_error_position() {
+        let source = "(Rule\n  (g (h \"\u{e9}\" b)\n\t";
+        let err = source.parse::<Expression>().unwrap_err();
+        assert_eq!(err, error(ErrorCode::UnbalancedParens, 8, 2, 3));
+
+        let source = "(Rule\n  (g (h \"\u{e9}\" b)))\n";
+        assert!(source.parse::<Expression>().is_ok());
+
+        let source = "(g\n\t(h \"\u{e9}\" x_?)))";
+        let err = source.parse::<Expression>().unwrap_err();
+        assert_eq!(err, error(ErrorCode::InvalidPattern, 15, 2, 12));
+        assert_eq!(err.to_string(), "invalid pattern at line 2, column 12");
+        assert_eq!(err.message(source),
+                   "invalid pattern at line 2, column 12\n  |\n2 | \t(h \"\u{e9}\" x_?)))\n  | \t          ^");
+    }
+
+    #[test]
+    fn parse_comments() {
+        let source = "; rules for the derivative
+                      #| (Rule (D x_ x_) 1)
+                         #| nested |# |#
+                      (Rule (D (Plus a_ b_) x_) ; linearity
+                            (Plus (D a x) #;(D c x) (D b x)))";
+        let expr = source.parse::<Expression>();
+        assert_eq!(format!("{:?}", expr.unwrap()), "(Rule (D (Plus a_ b_) x_) (Plus (D a x) (D b x)))");
+
+        let expr = "(f a;comment\n b #;#;c d e)".parse::<Expression>();
+        assert_eq!(format!("{:?}", expr.unwrap()), "(f a b e)");
+
+        let expr = "(f #a #b|c)".parse::<Expression>();
+        assert_eq!(format!("{:?}", expr.unwrap()), "(f #a #b|c)");
+
+        assert_eq!("(f #| a |# #| b )".parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::UnterminatedComment, 11, 1, 12));
+        assert!("; only a comment".parse::<Expression>().is_err());
+    }
+
+    #[test]
+    fn parse_trailing() {
+        assert_eq!("(f a) b".parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::TrailingCharacters, 6, 1, 7));
+        assert_eq!("(f a))".parse::<Expression>().unwrap_err(),
+                   error(ErrorCode::UnbalancedParens, 5, 1, 6));
+        assert!("(f a) ; comment\n".parse::<Expression>().is_ok());
+    }
+
+    #[test]
+    fn parse_all() {
+        let mut parser = Parser::new("a (f b) #| c |# \"d\" ; e".chars());
+        let exprs = parser.parse_all().unwrap();
+        assert_eq!(format!("{:?}", exprs), "[a, (f b), \"d\"]");
+
+        assert_eq!(Parser::new(" ; nothing".chars()).count(), 0);
+
+        let mut parser = Parser::new("a (f b))\n(g c)".chars());
+        assert!(parser.next().unwrap().is_ok());
+        assert!(parser.next().unwrap().is_ok());
+        assert_eq!(parser.next().unwrap().unwrap_err(),
+                   error(ErrorCode::UnbalancedParens, 7, 1, 8));
+        assert!(parser.next().is_none());
+    }
+
+    #[test]
+    fn parse_sequence() {
+        let expr = "(Sequence a b)".parse::<Expression>().unwrap();
+        assert!(expr.is_sequence());
+        assert_eq!(format!("{:?}", expr), "(Sequence a b)");
+
+        // a literal sequence within a list is not spliced by the parser
+        let expr = "(f (Sequence a b) c)".parse::<Expression>().unwrap();
+        assert_eq!(expr.children().len(), 3);
+        assert!(expr.get(&[1]).unwrap().is_sequence());
+        assert_eq!(format!("{:?}", expr), "(f (Sequence a b) c)");
+    }
+
+    #[test]
+    fn parse_optional() {
+        for p in &["x_:0", "(Plus a_ b_:0)", "x_f:(f 0)", "x_?t:1 | y_", "(h y_):(h 0)"] {
+            let expr = p.parse::<Expression>();
+            assert_eq!(format!("{:?}", expr.unwrap()), *p);
+        }
+
+        assert!("x_:".parse::<Expression>().is_err());
+        assert!("(f x_:)".parse::<Expression>().is_err());
+
+        // a ':' which does not follow a pattern is part of the symbol
+        assert_eq!("12:30".parse::<Expression>(), Ok(Expression::Atom("12:30".to_string())));
+        let expr = "(f a:b)".parse::<Expression>().unwrap();
+        assert_eq!(expr, Expression::List(vec![Expression::Atom("f".to_string()),
+                                               Expression::Atom("a:b".to_string())]));
+    }
+
+    #[test]
+    fn equality() {
+        let parse = |s: &str| s.parse::<Expression>().unwrap();
+
+        assert_eq!(parse("(f a 1 \"s\" x_:0)"), parse("(f a 1 \"s\" x_:0)"));
+        assert!(parse("(f a)") != parse("(f b)"));
+        assert!(parse("1") != parse("1.0"));
+        assert!(parse("a") != parse("\"a\""));
+        assert_eq!(Expression::Float(::std::f64::NAN), Expression::Float(::std::f64::NAN));
+
+        let mut set = HashSet::new();
+        set.insert(parse("(f a)"));
+        set.insert(parse("(f a)"));
+        set.insert(parse("(f 1/2)"));
+        assert_eq!(set.len(), 2);
+        assert!(set.contains(&parse("(f 1/2)")));
+    }
+
+    #[test]
+    fn ordering() {
+        let parse = |s: &str| s.parse::<Expression>().unwrap();
+
+        match parse("(Plus (f b) c 2 (f a b) \"s\" 1/2 B b (g a) 1.5 -3 x_ 1)") {
+            Expression::List(mut args) => {
+                args.sort();
+                assert_eq!(format!("{:?}", Expression::List(args)),
+                           "(-3 1/2 1 1.5 2 \"s\" b B c Plus (f b) (g a) (f a b) x_)");
+            }
+            _ => panic!("not a list")
+        }
+
+        assert!(parse("1") < parse("1.0"));
+        assert!(parse("12345678901234567890") > parse("1.0e10"));
+        assert!(parse("1.0e100") < Expression::Float(::std::f64::NAN));
+
+        // equal values in different representations are not equal
+        let two = Expression::BigInteger(BigInt::from_i64(2));
+        assert!(parse("2") < two && two < parse("2.0"));
+        let (half, unreduced) = (parse("1/2"),
+                                 Expression::Rational(BigInt::from_i64(2), BigInt::from_i64(4)));
+        assert!(half != unreduced && half.cmp(&unreduced) != Ordering::Equal);
+        let (zero, negative_zero) = (parse("0.0"), parse("-0.0"));
+        assert!(zero != negative_zero && zero.cmp(&negative_zero) != Ordering::Equal);
+    }
+
+    #[test]
+    fn subexpressions() {
+        let expr = "(f (g a) (Sequence) b)".parse::<Expression>().unwrap();
+
+        let pre: Vec<String> = expr.subexpressions()
+            .map(|(p, e)| format!("{:?} {:?}", p, e)).collect();
+        assert_eq!(pre, vec!["[] (f (g a) (Sequence) b)", "[0] f", "[1] (g a)", "[1, 0] g",
+                             "[1, 1] a", "[2] (Sequence)", "[3] b"]);
+
+        let post: Vec<String> = expr.subexpressions_post_order()
+            .map(|(p, e)| format!("{:?} {:?}", p, e)).collect();
+        assert_eq!(post, vec!["[0] f", "[1, 0] g", "[1, 1] a", "[1] (g a)", "[2] (Sequence)",
+                              "[3] b", "[] (f (g a) (Sequence) b)"]);
+
+        for (p, e) in expr.subexpressions() {
+            assert_eq!(expr.get(&p), Some(e));
+        }
+
+        let a = "a".parse::<Expression>().unwrap();
+        assert_eq!(a.subexpressions().count(), 1);
+        assert_eq!(a.subexpressions_post_order().count(), 1);
+    }
+
+    #[test]
+    fn get_and_replace_at() {
+        let mut expr = "(f (g a) b)".parse::<Expression>().unwrap();
+
+        assert_eq!(format!("{:?}", expr.get(&[1]).unwrap()), "(g a)");
+        assert!(expr.get(&[1, 2]).is_none());
+        assert!(expr.get(&[2, 0]).is_none());
+
+        *expr.get_mut(&[1, 0]).unwrap() = "h".parse::<Expression>().unwrap();
+        assert_eq!(format!("{:?}", expr), "(f (h a) b)");
+        assert!(expr.get_mut(&[0, 0]).is_none());
+
+        let seq = "(Sequence x y)".parse::<Expression>().unwrap();
+        let res = expr.replace_at(&[1, 1], seq.clone()).unwrap();
+        assert_eq!(format!("{:?}", res), "(f (h x y) b)");
+        let res = expr.replace_at(&[], seq.clone()).unwrap();
+        assert_eq!(format!("{:?}", res), "(Sequence x y)");
+        assert!(expr.replace_at(&[3], seq).is_none());
+    }
+
+    #[test]
+    fn positions_and_cases() {
+        let expr = "(f (f a) (g (f b)) c)".parse::<Expression>().unwrap();
+        let p = "(f x_)".parse::<Expression>().unwrap();
+
+        assert_eq!(expr.positions(&p, LevelSpec::all()), vec![vec![1], vec![2, 1]]);
+        assert_eq!(expr.positions(&p, LevelSpec::exactly(2)), vec![vec![2, 1]]);
+        assert_eq!(expr.count(&p, LevelSpec::up_to(1)), 1);
+        assert_eq!(expr.count(&p, LevelSpec::leaves()), 0);
+
+        let cases: Vec<String> = expr.cases(&p, LevelSpec::all()).iter()
+            .map(|&(e, ref bs)| format!("{:?} {:?}", e, bs["x"])).collect();
+        assert_eq!(cases, vec!["(f a) Expression(a)", "(f b) Expression(b)"]);
+
+        let any = "_Symbol".parse::<Expression>().unwrap();
+        assert_eq!(expr.count(&any, LevelSpec::leaves()), 7);
+        assert_eq!(expr.positions(&any, LevelSpec::between(-1, 1)), vec![vec![0], vec![3]]);
+    }
+
+    #[test]
+    fn positions_in_context() {
+        let mut ctx = Context::new();
+        ctx.set_attribute("Plus", Attribute::Orderless);
+        ctx.add_test("odd", |b: &Binding| match b.as_expression() {
+            Some(&Expression::Integer(n)) => n % 2 != 0,
+            _ => false
+        });
+
+        let expr = "(f (Plus 1 a) (Plus b 3) (Plus 2 c))".parse::<Expression>().unwrap();
+        let p = "(Plus x_?odd y_)".parse::<Expression>().unwrap();
+
+        // without the context neither the test nor the attribute applies
+        assert_eq!(expr.count(&p, LevelSpec::all()), 0);
+        assert_eq!(expr.positions_in(&p, LevelSpec::all(), &ctx), vec![vec![1], vec![2]]);
+        assert_eq!(expr.count_in(&p, LevelSpec::exactly(1), &ctx), 2);
+
+        let cases: Vec<String> = expr.cases_in(&p, LevelSpec::all(), &ctx).iter()
+            .map(|&(_, ref bs)| format!("{:?} {:?}", bs["x"], bs["y"])).collect();
+        assert_eq!(cases, vec!["Expression(1) Expression(a)", "Expression(3) Expression(b)"]);
+    }
+
     #[test]
     fn replace() {
         let expr = "(x z)".parse::<Expression>().unwrap();
@@ -253,6 +1334,25 @@ mod tests {
         assert_eq!(format!("{:?}", res), "((y r) (y s))");
     }
 
+    #[test]
+    fn replace_sequence() {
+        let expr = "(g (f a b) c)".parse::<Expression>().unwrap();
+        let pattern = "(f x__)".parse::<Expression>().unwrap();
+        let template = "x".parse::<Expression>().unwrap();
+
+        let res = expr.replace_all(&pattern, template.clone());
+        assert_eq!(format!("{:?}", res), "(g a b c)");
+
+        let res = "(f a b)".parse::<Expression>().unwrap().replace_all(&pattern, template);
+        assert!(res.is_sequence());
+        assert_eq!(format!("{:?}", res), "(Sequence a b)");
+
+        // sequences in templates are spliced as well
+        let template = "(h (Sequence x x))".parse::<Expression>().unwrap();
+        let res = expr.replace_all(&pattern, template);
+        assert_eq!(format!("{:?}", res), "(g (h a b a b) c)");
+    }
+
     #[test]
     fn replace_repeated() {
         let expr = "(x (x (x z)))".parse::<Expression>().unwrap();
@@ -263,4 +1363,53 @@ mod tests {
 
         assert_eq!(format!("{:?}", res), "(y (y (y z)))");
     }
+
+    #[test]
+    fn replace_all_rules() {
+        let expr = "(f (g a) (h b) (g (h c)))".parse::<Expression>().unwrap();
+        let rules = "((Rule (g x_) (G x)) (Rule (h x_) (H x)) (Rule (_ (h x_)) never))"
+            .parse::<RuleSet>().unwrap();
+
+        let res = expr.replace_all_rules(&rules);
+
+        // the first matching rule wins and replaced expressions are not
+        // visited again
+        assert_eq!(format!("{:?}", res), "(f (G a) (H b) (G (h c)))");
+    }
+
+    #[test]
+    fn replace_repeated_rules() {
+        let expr = "(Plus (Plus a a) (Plus a a))".parse::<Expression>().unwrap();
+        let rules = "((Rule (Plus x_ x_) (Times 2 x)) (Rule (Times 2 (Times 2 x_)) (Times 4 x)))"
+            .parse::<RuleSet>().unwrap();
+
+        let res = expr.replace_repeated_rules(&rules, 10).unwrap();
+
+        assert_eq!(format!("{:?}", res), "(Times 4 a)");
+    }
+
+    #[test]
+    fn try_replace_repeated() {
+        let expr = "(x (x (x z)))".parse::<Expression>().unwrap();
+        let pattern = "(x a_)".parse::<Expression>().unwrap();
+        let template = "(y a)".parse::<Expression>().unwrap();
+
+        let res = expr.try_replace_repeated(&pattern, template.clone(), 3).unwrap();
+        assert_eq!(format!("{:?}", res), "(y (y (y z)))");
+
+        let err = expr.try_replace_repeated(&pattern, template, 2).unwrap_err();
+        assert_eq!(err.steps, 2);
+        assert_eq!(format!("{:?}", err.expression), "(y (y (x z)))");
+    }
+
+    #[test]
+    fn try_replace_repeated_loop() {
+        let expr = "(f a)".parse::<Expression>().unwrap();
+        let pattern = "(f x_)".parse::<Expression>().unwrap();
+        let template = "(f (g x))".parse::<Expression>().unwrap();
+
+        let err = expr.try_replace_repeated(&pattern, template, 5).unwrap_err();
+        assert_eq!(err.steps, 5);
+        assert_eq!(format!("{:?}", err.expression), "(f (g (g (g (g (g a))))))");
+    }
 }
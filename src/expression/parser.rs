@@ -13,34 +13,200 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
+use std::error::Error;
+use std::fmt;
+use std::iter::Peekable;
+
 use expression::Expression;
+use number::Number;
+
+/// The `Parser` reads expressions from a stream of characters.
+///
+/// Used as an iterator it yields every top-level expression of the input.
+/// It stops after the first error.
+///
+/// # Example
+/// ```
+/// use ers::{Expression, Parser};
+///
+/// let source = "(Rule a b) ; first rule
+///               (Rule b c)";
+///
+/// let exprs = Parser::new(source.chars()).parse_all().unwrap();
+///
+/// assert_eq!(exprs.len(), 2);
+/// ```
+#[unstable(feature = "ers1")]
+pub struct Parser<T: Iterator<Item=char>> {
+    iter: Peekable<T>,
+    ch: Option<char>,
+    // the position of `ch`
+    pos: Position,
+    // set after an error so that iterating stops
+    failed: bool,
+    // the start positions of the elements of the last closed list
+    starts: Vec<Position>,
+}
+
+/// A position in the parsed input.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
+pub struct Position {
+    /// The offset in bytes starting at 0
+    pub offset: usize,
+    /// The line starting at 1
+    pub line: usize,
+    /// The column in characters starting at 1
+    pub column: usize,
+}
 
-pub struct Parser<T> {
-    iter: T,
-    ch: Option<char>
+#[unstable(feature = "ers1")]
+impl Position {
+    /// Returns the position of the first character.
+    #[unstable(feature = "ers1")]
+    pub fn start() -> Position {
+        Position { offset: 0, line: 1, column: 1 }
+    }
 }
 
+/// The kinds of syntax errors.
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
 pub enum ErrorCode {
+    /// A malformed blank or pattern like `x_?` or `x____`
     InvalidPattern,
+    /// A list is missing its opening or closing parenthesis
     UnbalancedParens,
+    /// There is no expression to parse
     EmptyInput,
+    /// A string literal is missing its closing quote
+    UnterminatedString,
+    /// An unknown escape sequence in a string literal like `\q`
+    InvalidEscape,
+    /// A block comment is missing its closing `|#`
+    UnterminatedComment,
+    /// There is more input after the expression
+    TrailingCharacters,
 }
 
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match *self {
+            ErrorCode::InvalidPattern => "invalid pattern",
+            ErrorCode::UnbalancedParens => "unbalanced parentheses",
+            ErrorCode::EmptyInput => "expected an expression",
+            ErrorCode::UnterminatedString => "unterminated string literal",
+            ErrorCode::InvalidEscape => "invalid escape sequence",
+            ErrorCode::UnterminatedComment => "unterminated block comment",
+            ErrorCode::TrailingCharacters => "trailing characters after the expression",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// The error returned if parsing fails.
 #[derive(Clone, Copy, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
 pub enum ParserError {
-    // TODO: add line/col
-    /// msg
-    SyntaxError(ErrorCode),
+    /// The input is malformed at the given position
+    SyntaxError(ErrorCode, Position),
     /// should not happen, if you see this there is some bug
     InternalError
 }
 
+#[unstable(feature = "ers1")]
+impl ParserError {
+    /// Returns the error message followed by the line of the source the
+    /// error occurred in, with a caret pointing at the position.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::Expression;
+    ///
+    /// let source = "(f a\n   b_?)";
+    /// let err = source.parse::<Expression>().unwrap_err();
+    ///
+    /// // invalid pattern at line 2, column 7
+    /// //   |
+    /// // 2 |    b_?)
+    /// //   |       ^
+    /// println!("{}", err.message(source));
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn message(&self, source: &str) -> String {
+        match *self {
+            ParserError::SyntaxError(_, pos) => excerpt(self, source, pos),
+            ParserError::InternalError => self.to_string(),
+        }
+    }
+}
+
+// Formats the message followed by the line of the source containing the
+// position, with a caret pointing at it.
+pub fn excerpt(msg: &fmt::Display, source: &str, pos: Position) -> String {
+    let line = source.split('\n').nth(pos.line - 1).unwrap_or("").trim_end_matches('\r');
+    let gutter = pos.line.to_string();
+    let pad: String = gutter.chars().map(|_| ' ').collect();
+
+    // keep tabs so that the caret lines up with the excerpt
+    let mut caret: String = line.chars().take(pos.column - 1)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    caret.push('^');
+
+    format!("{}\n{} |\n{} | {}\n{} | {}", msg, pad, gutter, line, pad, caret)
+}
+
+impl fmt::Display for ParserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParserError::SyntaxError(code, pos) => {
+                write!(f, "{} at line {}, column {}", code, pos.line, pos.column)
+            }
+            ParserError::InternalError => write!(f, "internal parser error"),
+        }
+    }
+}
+
+impl Error for ParserError {}
+
+#[unstable(feature = "ers1")]
+impl<T: Iterator<Item=char>> Iterator for Parser<T> {
+    type Item = Result<Expression, ParserError>;
+
+    fn next(&mut self) -> Option<Result<Expression, ParserError>> {
+        if self.failed {
+            return None;
+        }
+
+        let res = self.skip_whitespace().and_then(|_| {
+            match self.ch {
+                None => Ok(None),
+                Some(_) => self.parse_expression().map(Some)
+            }
+        });
+
+        match res {
+            Ok(e) => e.map(Ok),
+            Err(err) => {
+                self.failed = true;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[unstable(feature = "ers1")]
 impl<T: Iterator<Item=char>> Parser<T> {
+    /// Creates a parser reading from the characters.
+    #[unstable(feature = "ers1")]
     pub fn new(it: T) -> Parser<T> {
         let mut p = Parser {
-            iter: it,
+            iter: it.peekable(),
             ch: None,
+            pos: Position::start(),
+            failed: false,
+            starts: Vec::new(),
         };
 
         // go to the first char
@@ -50,11 +216,31 @@ impl<T: Iterator<Item=char>> Parser<T> {
     }
 
     // root ::= expression
+    /// Parses the next expression. The remaining input is left for
+    /// subsequent calls.
+    #[unstable(feature = "ers1")]
     pub fn parse(&mut self) -> Result<Expression, ParserError> {
-        self.skip_whitespace();
+        try!{ self.skip_whitespace() };
         self.parse_expression()
     }
 
+    /// Parses all remaining expressions.
+    #[unstable(feature = "ers1")]
+    pub fn parse_all(&mut self) -> Result<Vec<Expression>, ParserError> {
+        self.collect()
+    }
+
+    /// Checks that only whitespace and comments are left in the input.
+    #[unstable(feature = "ers1")]
+    pub fn end(&mut self) -> Result<(), ParserError> {
+        try!{ self.skip_whitespace() };
+        match self.ch {
+            None => Ok(()),
+            Some(')') => Err(self.error(ErrorCode::UnbalancedParens)),
+            Some(_) => Err(self.error(ErrorCode::TrailingCharacters)),
+        }
+    }
+
     // expression ::= '(' expression* ')'
     //            ::| blank
     //            ::| blank_seq
@@ -63,37 +249,108 @@ impl<T: Iterator<Item=char>> Parser<T> {
     //            ::| pattern_seq
     //            ::| pattern_null_seq
     //            ::| atom
+    //            ::| expression '?' test
+    //            ::| expression '|' expression
+    //            ::| expression ':' expression
+    //
+    // blanks and patterns can be followed by a head, e.g. `x_f`
     //
-    // filter first if list or not
     // EOF is invalid as it should not be called in that case
     fn parse_expression(&mut self) -> Result<Expression, ParserError> {
+        let e = try!{ self.parse_optional() };
+
+        try!{ self.skip_whitespace() };
+        if self.ch != Some('|') {
+            return Ok(e);
+        }
+
+        let mut v: Vec<Expression> = vec![e];
+        while self.ch == Some('|') {
+            // consume '|'
+            self.bump();
+            try!{ self.skip_whitespace() };
+
+            let alt = try!{ self.parse_optional() };
+            v.push(alt);
+
+            try!{ self.skip_whitespace() };
+        }
+
+        Ok(Expression::Alternatives(v))
+    }
+
+    fn parse_optional(&mut self) -> Result<Expression, ParserError> {
+        let e = try!{ self.parse_test_expression() };
+
+        if self.ch != Some(':') {
+            return Ok(e);
+        }
+
+        // consume ':'
+        self.bump();
+
+        let d = try!{ self.parse_primary() };
+        Ok(Expression::Optional(Box::new(e), Box::new(d)))
+    }
+
+    fn parse_test_expression(&mut self) -> Result<Expression, ParserError> {
+        let mut e = try!{ self.parse_primary() };
+
+        while self.ch == Some('?') {
+            // consume '?'
+            self.bump();
+
+            let t = try!{ self.parse_test() };
+            e = Expression::PatternTest(Box::new(e), t);
+        }
+
+        Ok(e)
+    }
+
+    // filter first if list or not
+    fn parse_primary(&mut self) -> Result<Expression, ParserError> {
         match self.ch {
             Some('(') => self.parse_list(),
-            Some(')') => Err(ParserError::SyntaxError(ErrorCode::UnbalancedParens)),
+            Some('"') => self.parse_string(),
+            Some(')') => Err(self.error(ErrorCode::UnbalancedParens)),
+            Some('?') | Some('|') | Some(':') => Err(self.error(ErrorCode::InvalidPattern)),
             // EOF
-            None => Err(ParserError::SyntaxError(ErrorCode::EmptyInput)),
+            None => Err(self.error(ErrorCode::EmptyInput)),
             _ => self.parse_atomic(),
         }
     }
 
     // parse expressions until list is properly treminated by ')'
     fn parse_list(&mut self) -> Result<Expression, ParserError> {
+        // an unclosed list is reported at its '('
+        let start = self.pos;
+
         // consume '('
         self.bump();
 
         let mut v: Vec<Expression> = Vec::new();
+        let mut starts: Vec<Position> = Vec::new();
         loop {
-            self.skip_whitespace();
+            try!{ self.skip_whitespace() };
             match self.ch {
                 Some(')') => {
                     // consume ')'
                     self.bump();
-
-                    return Ok(Expression::List(v))
+                    self.starts = starts;
+
+                    // only the list itself becomes a sequence, nested
+                    // sequences are spliced when a rule substitutes them
+                    return match v.first() {
+                        Some(&Expression::Atom(ref s)) if s == "Sequence" => {
+                            Ok(Expression::Sequence(v[1..].to_vec()))
+                        }
+                        _ => Ok(Expression::List(v))
+                    }
                 }
                 // EOF
-                None => { return Err(ParserError::SyntaxError(ErrorCode::UnbalancedParens)); }
+                None => { return Err(ParserError::SyntaxError(ErrorCode::UnbalancedParens, start)); }
                 _ => {
+                    starts.push(self.pos);
                     let exp = try!{ self.parse_expression() };
                     v.push(exp);
                 }
@@ -101,13 +358,107 @@ impl<T: Iterator<Item=char>> Parser<T> {
         }
     }
 
-    // parse until terminated by '(', ')', whitespace or EOF
-    // A '_' termination indicates a pattern/blank type
+    // parse characters until the closing '"', a '\' starts an escape
+    // sequence
+    fn parse_string(&mut self) -> Result<Expression, ParserError> {
+        // an unterminated string is reported at its '"'
+        let start = self.pos;
+
+        // consume '"'
+        self.bump();
+
+        let mut s: String = String::new();
+        loop {
+            match self.ch {
+                Some('"') => {
+                    // consume '"'
+                    self.bump();
+
+                    return Ok(Expression::String(s));
+                }
+                Some('\\') => {
+                    // consume '\'
+                    self.bump();
+
+                    let c = match self.parse_escape() {
+                        Err(ParserError::SyntaxError(ErrorCode::UnterminatedString, _)) => {
+                            return Err(ParserError::SyntaxError(ErrorCode::UnterminatedString, start));
+                        }
+                        r => try!{ r }
+                    };
+                    s.push(c);
+                }
+                Some(c) => {
+                    s.push(c);
+                    self.bump();
+                }
+                None => { return Err(ParserError::SyntaxError(ErrorCode::UnterminatedString, start)); }
+            }
+        }
+    }
+
+    // parse the escape sequence following a '\'
+    fn parse_escape(&mut self) -> Result<char, ParserError> {
+        let c = match self.ch {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('"') => '"',
+            Some('\\') => '\\',
+            Some('u') => {
+                // consume 'u'
+                self.bump();
+                return self.parse_unicode_escape();
+            }
+            None => { return Err(self.error(ErrorCode::UnterminatedString)); }
+            _ => { return Err(self.error(ErrorCode::InvalidEscape)); }
+        };
+        self.bump();
+        Ok(c)
+    }
+
+    // parse the '{XXXX}' part of a '\u{XXXX}' escape
+    fn parse_unicode_escape(&mut self) -> Result<char, ParserError> {
+        if self.ch != Some('{') {
+            return Err(self.error(ErrorCode::InvalidEscape));
+        }
+        self.bump();
+
+        let mut hex = String::new();
+        while let Some(c) = self.ch {
+            if !c.is_digit(16) {
+                break;
+            }
+            hex.push(c);
+            self.bump();
+        }
+        match self.ch {
+            Some('}') => {}
+            None => { return Err(self.error(ErrorCode::UnterminatedString)); }
+            // e.g. the closing quote if the '}' is missing
+            _ => { return Err(self.error(ErrorCode::InvalidEscape)); }
+        }
+        // consume '}'
+        self.bump();
+
+        if hex.len() == 0 || hex.len() > 6 {
+            return Err(self.error(ErrorCode::InvalidEscape));
+        }
+        match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+            Some(c) => Ok(c),
+            None => Err(self.error(ErrorCode::InvalidEscape))
+        }
+    }
+
+    // parse until terminated by '(', ')', '"', ';', whitespace or EOF.
+    // A '_' termination indicates a pattern/blank type. Tokens which are
+    // numbers become numeric leaves, all others are symbols.
     fn parse_atomic(&mut self) -> Result<Expression, ParserError> {
         let mut s: String = String::new();
 
         loop {
-            if self.ch_is_terminator() {
+            if self.ch_is_delimiter() {
                 break
             }
 
@@ -122,7 +473,7 @@ impl<T: Iterator<Item=char>> Parser<T> {
                 }
             }
 
-            // safe as we checked for None in self.ch_is_terminator
+            // safe as we checked for None in self.ch_is_delimiter
             s.push(self.ch.unwrap());
 
             self.bump();
@@ -131,7 +482,10 @@ impl<T: Iterator<Item=char>> Parser<T> {
         if s.len() == 0 {
             Err(ParserError::InternalError)
         } else {
-            Ok(Expression::Atom(s))
+            match s.parse::<Number>() {
+                Ok(n) => Ok(n.to_expression()),
+                Err(_) => Ok(Expression::Atom(s))
+            }
         }
     }
 
@@ -142,12 +496,8 @@ impl<T: Iterator<Item=char>> Parser<T> {
             return self.parse_blank_seq();
         }
 
-        if !self.ch_is_terminator() {
-            // invalid termination
-            return Err(ParserError::SyntaxError(ErrorCode::InvalidPattern));
-        }
-
-        Ok(Expression::Blank)
+        let h = try!{ self.parse_head() };
+        Ok(Expression::Blank(h))
     }
 
     fn parse_blank_seq(&mut self) -> Result<Expression, ParserError> {
@@ -157,21 +507,13 @@ impl<T: Iterator<Item=char>> Parser<T> {
             return self.parse_blank_null_seq();
         }
 
-        if !self.ch_is_terminator() {
-            // invalid termination
-            return Err(ParserError::SyntaxError(ErrorCode::InvalidPattern));
-        }
-
-        Ok(Expression::BlankSeq)
+        let h = try!{ self.parse_head() };
+        Ok(Expression::BlankSeq(h))
     }
 
     fn parse_blank_null_seq(&mut self) -> Result<Expression, ParserError> {
-        if !self.ch_is_terminator() {
-            // invalid termination
-            return Err(ParserError::SyntaxError(ErrorCode::InvalidPattern));
-        }
-
-        Ok(Expression::BlankNullSeq)
+        let h = try!{ self.parse_head() };
+        Ok(Expression::BlankNullSeq(h))
     }
 
     fn parse_pattern(&mut self, s: String) -> Result<Expression, ParserError> {
@@ -181,12 +523,8 @@ impl<T: Iterator<Item=char>> Parser<T> {
             return self.parse_pattern_seq(s);
         }
 
-        if !self.ch_is_terminator() {
-            // invalid termination
-            return Err(ParserError::SyntaxError(ErrorCode::InvalidPattern));
-        }
-
-        Ok(Expression::Pattern(s))
+        let h = try!{ self.parse_head() };
+        Ok(Expression::Pattern(s, h))
     }
 
     fn parse_pattern_seq(&mut self, s: String) -> Result<Expression, ParserError> {
@@ -196,30 +534,76 @@ impl<T: Iterator<Item=char>> Parser<T> {
             return self.parse_pattern_null_seq(s);
         }
 
-        if !self.ch_is_terminator() {
-            // invalid termination
-            return Err(ParserError::SyntaxError(ErrorCode::InvalidPattern));
+        let h = try!{ self.parse_head() };
+        Ok(Expression::PatternSeq(s, h))
+    }
+
+    fn parse_pattern_null_seq(&mut self, s: String) -> Result<Expression, ParserError> {
+        let h = try!{ self.parse_head() };
+        Ok(Expression::PatternNullSeq(s, h))
+    }
+
+    // parse the optional head following the underscores of a blank or
+    // pattern until terminated by '(', ')', '?', '|', ':', whitespace or EOF
+    fn parse_head(&mut self) -> Result<Option<String>, ParserError> {
+        let mut h: String = String::new();
+
+        while !self.ch_is_terminator() {
+            if self.ch == Some('_') {
+                // invalid termination
+                return Err(self.error(ErrorCode::InvalidPattern));
+            }
+
+            // safe as we checked for None in self.ch_is_terminator
+            h.push(self.ch.unwrap());
+
+            self.bump();
         }
 
-        Ok(Expression::PatternSeq(s))
+        if h.len() == 0 {
+            Ok(None)
+        } else {
+            Ok(Some(h))
+        }
     }
 
-    fn parse_pattern_null_seq(&mut self, s: String) -> Result<Expression, ParserError> {
-        if !self.ch_is_terminator() {
-            // invalid termination
-            return Err(ParserError::SyntaxError(ErrorCode::InvalidPattern));
+    // parse the name of a pattern test following '?'
+    fn parse_test(&mut self) -> Result<String, ParserError> {
+        let mut t: String = String::new();
+
+        while !self.ch_is_terminator() {
+            // safe as we checked for None in self.ch_is_terminator
+            t.push(self.ch.unwrap());
+
+            self.bump();
         }
 
-        Ok(Expression::PatternNullSeq(s))
+        if t.len() == 0 {
+            Err(self.error(ErrorCode::InvalidPattern))
+        } else {
+            Ok(t)
+        }
     }
 
-    fn ch_is_terminator(&self) -> bool {
+    // the characters ending a symbol
+    fn ch_is_delimiter(&self) -> bool {
         self.ch_is_whitespace()
         || self.ch == Some('(')
         || self.ch == Some(')')
+        || self.ch == Some('"')
+        || self.ch == Some(';')
         || self.ch == None
     }
 
+    // the characters ending the head of a blank or pattern and the name of
+    // a test, as '?' and '|' are only special right after them
+    fn ch_is_terminator(&self) -> bool {
+        self.ch_is_delimiter()
+        || self.ch == Some('?')
+        || self.ch == Some('|')
+        || self.ch == Some(':')
+    }
+
     fn ch_is_whitespace(&self) -> bool {
         match self.ch {
             None => false,
@@ -227,13 +611,103 @@ impl<T: Iterator<Item=char>> Parser<T> {
         }
     }
 
-    fn skip_whitespace(&mut self) {
-        while self.ch_is_whitespace() {
-            self.ch = self.iter.next();
+    // skip whitespace and comments, which are
+    //   ';' until the end of the line
+    //   '#|' until the matching '|#' as block comments nest
+    //   '#;' followed by an expression
+    fn skip_whitespace(&mut self) -> Result<(), ParserError> {
+        loop {
+            if self.ch_is_whitespace() {
+                self.bump();
+            } else if self.ch == Some(';') {
+                while self.ch.is_some() && self.ch != Some('\n') {
+                    self.bump();
+                }
+            } else if self.ch == Some('#') && self.peek() == Some('|') {
+                try!{ self.skip_block_comment() };
+            } else if self.ch == Some('#') && self.peek() == Some(';') {
+                // consume '#;'
+                self.bump();
+                self.bump();
+
+                try!{ self.skip_whitespace() };
+                try!{ self.parse_expression() };
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    fn skip_block_comment(&mut self) -> Result<(), ParserError> {
+        // an unterminated comment is reported at its '#|'
+        let start = self.pos;
+
+        // consume '#|'
+        self.bump();
+        self.bump();
+
+        let mut depth = 1;
+        loop {
+            match (self.ch, self.peek()) {
+                (Some('|'), Some('#')) => {
+                    self.bump();
+                    self.bump();
+
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                (Some('#'), Some('|')) => {
+                    self.bump();
+                    self.bump();
+
+                    depth += 1;
+                }
+                (Some(_), _) => self.bump(),
+                (None, _) => {
+                    return Err(ParserError::SyntaxError(ErrorCode::UnterminatedComment, start));
+                }
+            }
         }
     }
 
+    fn peek(&mut self) -> Option<char> {
+        self.iter.peek().cloned()
+    }
+
     fn bump(&mut self) {
+        if let Some(c) = self.ch {
+            self.pos.offset += c.len_utf8();
+            if c == '\n' {
+                self.pos.line += 1;
+                self.pos.column = 1;
+            } else {
+                self.pos.column += 1;
+            }
+        }
         self.ch = self.iter.next();
     }
+
+    fn error(&self, code: ErrorCode) -> ParserError {
+        ParserError::SyntaxError(code, self.pos)
+    }
+}
+
+// Parses the string like `Expression::from_str`. Returns the position where
+// the expression starts and, if it is a list, where each element starts.
+pub fn parse_with_starts(s: &str)
+                         -> Result<(Expression, Position, Vec<Position>), ParserError> {
+    let mut parser = Parser::new(s.chars());
+
+    try!{ parser.skip_whitespace() };
+    let start = parser.pos;
+    let e = try!{ parser.parse_expression() };
+    try!{ parser.end() };
+
+    let starts = match e {
+        Expression::List(_) => parser.starts,
+        _ => Vec::new()
+    };
+    Ok((e, start, starts))
 }
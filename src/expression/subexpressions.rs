@@ -0,0 +1,77 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use expression::Expression;
+
+/// An iterator over all subexpressions of an `Expression` and their
+/// positions, created by `subexpressions` and `subexpressions_post_order`.
+///
+/// The position of a subexpression is the path of indices leading to it
+/// from the root, i.e. `[]` for the expression itself and `[1, 0]` for the
+/// first element of the second element. Only lists and sequences have
+/// subexpressions.
+#[unstable(feature = "ers1")]
+pub struct Subexpressions<'a> {
+    // the subexpressions still to visit, the flag marks lists whose
+    // elements were already pushed
+    stack: Vec<(Vec<usize>, &'a Expression, bool)>,
+    post_order: bool,
+}
+
+// Creates an iterator over `e` and its subexpressions in pre-order or, if
+// `post_order` is set, in post-order.
+pub fn subexpressions<'a>(e: &'a Expression, post_order: bool) -> Subexpressions<'a> {
+    Subexpressions {
+        stack: vec![(Vec::new(), e, false)],
+        post_order: post_order,
+    }
+}
+
+impl<'a> Subexpressions<'a> {
+    fn push_children(&mut self, path: &[usize], e: &'a Expression) {
+        // push the last element first so that they are visited in order
+        for (i, c) in e.children().iter().enumerate().rev() {
+            let mut p = path.to_vec();
+            p.push(i);
+            self.stack.push((p, c, false));
+        }
+    }
+}
+
+#[unstable(feature = "ers1")]
+impl<'a> Iterator for Subexpressions<'a> {
+    type Item = (Vec<usize>, &'a Expression);
+
+    fn next(&mut self) -> Option<(Vec<usize>, &'a Expression)> {
+        loop {
+            let (path, e, expanded) = match self.stack.pop() {
+                Some(s) => s,
+                None => return None
+            };
+
+            if !self.post_order {
+                self.push_children(&path, e);
+                return Some((path, e));
+            }
+
+            if expanded || e.children().is_empty() {
+                return Some((path, e));
+            }
+
+            self.stack.push((path.clone(), e, true));
+            self.push_children(&path, e);
+        }
+    }
+}
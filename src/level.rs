@@ -0,0 +1,258 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp;
+use std::collections::HashSet;
+use std::isize;
+
+use expression::{self, Expression};
+use rule::RuleSet;
+
+/// A `LevelSpec` selects the subexpressions visited by queries like
+/// `Expression::positions`, in the manner of Mathematica's level
+/// specifications.
+///
+/// The level of a subexpression is the length of its position, i.e. the
+/// expression itself is at level 0 and its elements at level 1. Negative
+/// levels count from the bottom instead: level -n contains the
+/// subexpressions of depth n, where leaves have depth 1 and a list is one
+/// deeper than its deepest element. Level -1 therefore selects the leaves.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[unstable(feature = "ers1")]
+pub struct LevelSpec {
+    min: isize,
+    max: isize,
+}
+
+impl LevelSpec {
+    /// Selects all levels including the expression itself.
+    #[unstable(feature = "ers1")]
+    pub fn all() -> LevelSpec {
+        LevelSpec::between(0, isize::MAX)
+    }
+
+    /// Selects the levels 1 to `n`.
+    #[unstable(feature = "ers1")]
+    pub fn up_to(n: isize) -> LevelSpec {
+        LevelSpec::between(1, n)
+    }
+
+    /// Selects level `n` only.
+    #[unstable(feature = "ers1")]
+    pub fn exactly(n: isize) -> LevelSpec {
+        LevelSpec::between(n, n)
+    }
+
+    /// Selects the levels `min` to `max`, both inclusive.
+    ///
+    /// # Example
+    /// ```
+    /// use ers::LevelSpec;
+    ///
+    /// LevelSpec::between(2, 4); // depth 2 to 4 from the top
+    /// LevelSpec::between(1, -2); // all levels except the root and leaves
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn between(min: isize, max: isize) -> LevelSpec {
+        LevelSpec { min: min, max: max }
+    }
+
+    /// Selects the leaves, i.e. level -1.
+    #[unstable(feature = "ers1")]
+    pub fn leaves() -> LevelSpec {
+        LevelSpec::exactly(-1)
+    }
+
+    fn contains(&self, level: usize, depth: usize) -> bool {
+        let (level, depth) = (level as isize, -(depth as isize));
+        let lower = if self.min >= 0 { level >= self.min } else { depth >= self.min };
+        let upper = if self.max >= 0 { level <= self.max } else { depth <= self.max };
+        lower && upper
+    }
+}
+
+/// Calls `f` with every subexpression of `e` in the selected levels and its
+/// position, elements before the lists containing them.
+pub fn walk<'a, F>(e: &'a Expression, levels: LevelSpec, mut f: F)
+    where F: FnMut(&[usize], &'a Expression) {
+    fold(e, |e, path, depth, _: Vec<()>| {
+        if levels.contains(path.len(), depth) {
+            f(path, e);
+        }
+    });
+}
+
+/// Applies the rules once to every subexpression of `e` in the selected
+/// levels, deeper subexpressions first.
+pub fn replace_at_levels(e: &Expression, rules: &RuleSet, levels: LevelSpec) -> Expression {
+    replace_selected(e, rules, |path, depth| levels.contains(path.len(), depth))
+}
+
+/// Applies the rules once to the subexpressions of `e` at the positions,
+/// deeper subexpressions first. The positions refer to `e`, not to the
+/// intermediate results.
+pub fn replace_at_positions(e: &Expression, rules: &RuleSet, paths: &[Vec<usize>]) -> Expression {
+    let paths: HashSet<&[usize]> = paths.iter().map(|p| &p[..]).collect();
+    replace_selected(e, rules, |path, _| paths.contains(path))
+}
+
+// Rebuilds `e` from the rewritten elements and applies the rules to the
+// subexpressions for which `select` returns true given their position and
+// depth.
+fn replace_selected<F>(e: &Expression, rules: &RuleSet, select: F) -> Expression
+    where F: Fn(&[usize], usize) -> bool {
+    fold(e, |e, path, depth, elements| {
+        let new_e = match *e {
+            Expression::List(_) | Expression::Sequence(_) => {
+                let mut v = Vec::new();
+                for c in elements {
+                    expression::splice(&mut v, c);
+                }
+                match *e {
+                    Expression::List(_) => Expression::List(v),
+                    _ => Expression::Sequence(v)
+                }
+            }
+            _ => e.clone()
+        };
+
+        if select(path, depth) {
+            if let Some(res) = rules.apply(&new_e) {
+                return res;
+            }
+        }
+        new_e
+    })
+}
+
+// A list whose elements are being visited by `fold`.
+struct Frame<'a, T> {
+    e: &'a Expression,
+    // the results for the elements visited so far
+    results: Vec<T>,
+    // the depth of the deepest element visited so far
+    depth: usize,
+}
+
+// Visits the subexpressions of `e` elements first, using an explicit stack
+// like the matcher so that deep expressions do not overflow the call stack.
+// `leave` is called with every subexpression, its position and depth and
+// the results for its elements, and returns the result for the
+// subexpression.
+fn fold<'a, T, F>(e: &'a Expression, mut leave: F) -> T
+    where F: FnMut(&'a Expression, &[usize], usize, Vec<T>) -> T {
+    let mut path = Vec::new();
+    let mut stack = vec![Frame { e: e, results: Vec::new(), depth: 0 }];
+    loop {
+        let next = {
+            let top = stack.last().unwrap();
+            elements(top.e).get(top.results.len())
+        };
+
+        if let Some(c) = next {
+            path.push(stack.last().unwrap().results.len());
+            stack.push(Frame { e: c, results: Vec::new(), depth: 0 });
+            continue;
+        }
+
+        let done = stack.pop().unwrap();
+        let depth = match *done.e {
+            // an empty list is still deeper than a leaf
+            Expression::List(_) | Expression::Sequence(_) => cmp::max(done.depth, 1) + 1,
+            _ => 1
+        };
+        let res = leave(done.e, &path, depth, done.results);
+
+        match stack.last_mut() {
+            Some(parent) => {
+                path.pop();
+                parent.results.push(res);
+                parent.depth = cmp::max(parent.depth, depth);
+            }
+            None => { return res; }
+        }
+    }
+}
+
+fn elements(e: &Expression) -> &[Expression] {
+    match *e {
+        Expression::List(ref es) | Expression::Sequence(ref es) => es,
+        _ => &[]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expression::Expression;
+    use rule::RuleSet;
+    use super::{walk, replace_at_levels, replace_at_positions, LevelSpec};
+
+    fn levels(s: &str, levels: LevelSpec) -> Vec<String> {
+        let e = s.parse::<Expression>().unwrap();
+        let mut res = Vec::new();
+        walk(&e, levels, |p, e| res.push(format!("{:?} {:?}", p, e)));
+        res
+    }
+
+    #[test]
+    fn level_spec() {
+        let e = "(f (g a) b)";
+
+        assert_eq!(levels(e, LevelSpec::all()),
+                   vec!["[0] f", "[1, 0] g", "[1, 1] a", "[1] (g a)", "[2] b", "[] (f (g a) b)"]);
+        assert_eq!(levels(e, LevelSpec::up_to(1)), vec!["[0] f", "[1] (g a)", "[2] b"]);
+        assert_eq!(levels(e, LevelSpec::exactly(2)), vec!["[1, 0] g", "[1, 1] a"]);
+        assert_eq!(levels(e, LevelSpec::exactly(0)), vec!["[] (f (g a) b)"]);
+        assert_eq!(levels(e, LevelSpec::leaves()),
+                   vec!["[0] f", "[1, 0] g", "[1, 1] a", "[2] b"]);
+        assert_eq!(levels(e, LevelSpec::exactly(-2)), vec!["[1] (g a)"]);
+        assert_eq!(levels(e, LevelSpec::between(1, -2)), vec!["[1] (g a)"]);
+        assert_eq!(levels(e, LevelSpec::between(-3, 0)), vec!["[] (f (g a) b)"]);
+        assert_eq!(levels("(f ())", LevelSpec::leaves()), vec!["[0] f"]);
+    }
+
+    #[test]
+    fn replace_levels() {
+        let e = "(f (f a) (g (f b)))".parse::<Expression>().unwrap();
+        let rules = "(Rule (f x_) (h x))".parse::<RuleSet>().unwrap();
+
+        let res = replace_at_levels(&e, &rules, LevelSpec::exactly(1));
+        assert_eq!(format!("{:?}", res), "(f (h a) (g (f b)))");
+        let res = replace_at_levels(&e, &rules, LevelSpec::all());
+        assert_eq!(format!("{:?}", res), "(f (h a) (g (h b)))");
+        let res = replace_at_levels(&e, &rules, LevelSpec::exactly(-2));
+        assert_eq!(format!("{:?}", res), "(f (h a) (g (h b)))");
+
+        let rules = "(Rule a (Sequence b c))".parse::<RuleSet>().unwrap();
+        let res = replace_at_levels(&e, &rules, LevelSpec::leaves());
+        assert_eq!(format!("{:?}", res), "(f (f b c) (g (f b)))");
+    }
+
+    #[test]
+    fn replace_positions() {
+        let e = "(f a (g a) a)".parse::<Expression>().unwrap();
+        let rules = "(Rule a (Sequence))".parse::<RuleSet>().unwrap();
+
+        let res = replace_at_positions(&e, &rules, &[vec![1], vec![2, 1]]);
+        assert_eq!(format!("{:?}", res), "(f (g) a)");
+        let res = replace_at_positions(&e, &rules, &[vec![0], vec![5, 1]]);
+        assert_eq!(format!("{:?}", res), "(f a (g a) a)");
+        let res = replace_at_positions(&e, &rules, &[vec![]]);
+        assert_eq!(format!("{:?}", res), "(f a (g a) a)");
+        // duplicate positions are rewritten once
+        let res = replace_at_positions(&e, &rules, &[vec![3], vec![3]]);
+        assert_eq!(format!("{:?}", res), "(f a (g a))");
+    }
+}
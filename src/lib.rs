@@ -28,7 +28,7 @@
 //!
 //! We have several types of expressions where most of them are needed for
 //! matching. But we have two fundamental basic blocks one is an `Atom`
-//! and the other one is a `List`.  An `Atom` represents a string entry while
+//! and the other one is a `List`.  An `Atom` represents a symbol while
 //! a `List` contains a list of expressions. Denoted as S-Expressions we have
 //! for example `(x (y z))` which is a list of two expressions. The first is
 //! an `Atom` with the value `x`. The second is another `List` which itself
@@ -48,6 +48,9 @@
 //! format!("{:?}", ers); // => "(x (y z))"
 //! ```
 //!
+//! Besides symbols the leaves of an expression can be integers like `-12`,
+//! rationals like `3/4`, floats like `1.5` and string literals like `"a b"`.
+//!
 //! ### Parsing
 //!
 //! We can also build an `Expression` by parsing an S-Expression string.
@@ -60,6 +63,14 @@
 //! assert_eq!(format!("{:?}", ls.unwrap()), "(x (y z))");
 //! ```
 //!
+//! The source may contain comments. `;` starts a comment until the end of
+//! the line, `#| ... |#` is a block comment which can be nested and `#;`
+//! comments out the expression following it.
+//!
+//! `parse` fails if there is anything but a single expression. A source with
+//! several expressions, like a file of rules, is read with a
+//! [`Parser`](struct.Parser.html).
+//!
 //!
 //! ## Matching
 //!
@@ -74,6 +85,12 @@
 //! `__` as well as `x__`. Finally `BlankNullSeq` and `PatternNullSeq` use
 //! three underscores `___` and `x___`.
 //!
+//! Every blank and pattern can be restricted to expressions with a given head
+//! by appending the head after the underscores. `x_f` only matches lists
+//! starting with the atom `f` like `(f a b)`. `_Atom` and `_List` match any
+//! atom and any list respectively. For sequence patterns like `x__f` every
+//! element of the sequence has to have the given head.
+//!
 //! ```
 //! use ers::{Expression, Match};
 //!
@@ -84,6 +101,116 @@
 //! let bindings = expr.match_pattern(&pattern);
 //! ```
 //!
+//! A name may appear several times in a pattern. In that case all
+//! occurrences have to match structurally equal expressions.
+//!
+//! ```
+//! use ers::{Expression, Match};
+//!
+//! let pattern = "(Plus x_ x_)".parse::<Expression>().unwrap();
+//!
+//! let expr = "(Plus a a)".parse::<Expression>().unwrap();
+//! assert!(expr.match_pattern(&pattern).is_some());
+//!
+//! let expr = "(Plus a b)".parse::<Expression>().unwrap();
+//! assert!(expr.match_pattern(&pattern).is_none());
+//! ```
+//!
+//! `match_pattern` only returns the first match. If a pattern contains
+//! several sequences there can be more than one way to match an expression.
+//! All of them can be enumerated with `match_all`.
+//!
+//! ```
+//! use ers::{Expression, Match};
+//!
+//! let expr = "(x y z)".parse::<Expression>().unwrap();
+//! let pattern = "(___ a_ ___)".parse::<Expression>().unwrap();
+//!
+//! for bindings in expr.match_all(&pattern) {
+//!     // binds `a` to `x`, `y` and `z` respectively
+//! }
+//! ```
+//!
+//! Patterns can be guarded by tests written in Rust. `x_?name` only matches
+//! if the test registered as `name` in a [`Context`](struct.Context.html)
+//! accepts the binding of `x_`. If a test fails the matcher backtracks
+//! and tries the next possibility, e.g. the next split of a sequence.
+//!
+//! ```
+//! use ers::{Expression, Match, Binding, Context};
+//!
+//! let mut ctx = Context::new();
+//! ctx.add_test("long", |b: &Binding| {
+//!     b.as_sequence().map_or(false, |es| es.len() > 1)
+//! });
+//!
+//! let expr = "(f a b c)".parse::<Expression>().unwrap();
+//! let pattern = "(f x__?long y__)".parse::<Expression>().unwrap();
+//!
+//! // binds `x` to `a b` and `y` to `c`
+//! let bindings = expr.match_pattern_in(&pattern, &ctx).unwrap();
+//! ```
+//!
+//! Alternative patterns are separated by `|` and tried in order. Pattern
+//! variables that only occur in the alternatives which were not taken are
+//! bound to the empty sequence.
+//!
+//! ```
+//! use ers::{Expression, Match};
+//!
+//! let pattern = "(Sin x_) | (Cos x_)".parse::<Expression>().unwrap();
+//!
+//! let expr = "(Cos a)".parse::<Expression>().unwrap();
+//! assert!(expr.match_pattern(&pattern).is_some());
+//! ```
+//!
+//! Optional patterns like `x_:0` match a single expression if there is one
+//! and otherwise bind `x` to the given default value.
+//!
+//! ```
+//! use ers::{Expression, Match};
+//!
+//! let pattern = "(Plus a_ b_:0)".parse::<Expression>().unwrap();
+//!
+//! // binds `a` to `y` and `b` to `0`
+//! let expr = "(Plus y)".parse::<Expression>().unwrap();
+//! assert!(expr.match_pattern(&pattern).is_some());
+//! ```
+//!
+//! Heads can be declared `Orderless` in a `Context`. The arguments of such
+//! expressions are matched in any order, which is what we want for
+//! commutative functions.
+//!
+//! ```
+//! use ers::{Expression, Match, Context, Attribute};
+//!
+//! let mut ctx = Context::new();
+//! ctx.set_attribute("Plus", Attribute::Orderless);
+//!
+//! let expr = "(Plus a 0 b)".parse::<Expression>().unwrap();
+//! let pattern = "(Plus 0 rest___)".parse::<Expression>().unwrap();
+//!
+//! // binds `rest` to `a b`
+//! assert!(expr.match_pattern_in(&pattern, &ctx).is_some());
+//! ```
+//!
+//! Similarly heads can be declared `Flat`, i.e. associative. A single
+//! pattern can then match several arguments which are grouped under the
+//! same head. Sequence patterns are not affected by this.
+//!
+//! ```
+//! use ers::{Expression, Match, Context, Attribute};
+//!
+//! let mut ctx = Context::new();
+//! ctx.set_attribute("Plus", Attribute::Flat);
+//!
+//! let expr = "(Plus x y z)".parse::<Expression>().unwrap();
+//! let pattern = "(Plus a_ b_)".parse::<Expression>().unwrap();
+//!
+//! // binds `a` to `x` and `b` to `(Plus y z)`
+//! assert!(expr.match_pattern_in(&pattern, &ctx).is_some());
+//! ```
+//!
 //! ## Binding
 //!
 //! Once we successfully matched an expression and generated bindings we can use
@@ -108,6 +235,61 @@
 //!
 //! assert_eq!(format!("{:?}", replaced), "((y z) b)");
 //! ```
+//!
+//! ## Rewriting
+//!
+//! A [`Rule`](struct.Rule.html) combines a pattern with a template and is
+//! written `(Rule lhs rhs)`. Rules are collected in an ordered
+//! [`RuleSet`](struct.RuleSet.html) which also carries the `Context` the
+//! patterns are matched in. When rewriting an expression the rules are tried
+//! in order at every subexpression.
+//!
+//! ```
+//! use ers::{Expression, RuleSet};
+//!
+//! let rules = "((Rule (Plus x_ 0) x) (Rule (Times x_ 1) x))".parse::<RuleSet>().unwrap();
+//! let expr = "(Plus (Times y 1) 0)".parse::<Expression>().unwrap();
+//!
+//! let res = expr.replace_repeated_rules(&rules, 100).unwrap();
+//!
+//! assert_eq!(format!("{:?}", res), "y");
+//! ```
+//!
+//! The order in which subexpressions are rewritten can be chosen with a
+//! [`Traversal`](enum.Traversal.html), e.g. `Innermost` for strict and
+//! `Outermost` for lazy evaluation.
+//!
+//! ```
+//! use ers::{Expression, RuleSet, Traversal};
+//!
+//! let rules = "((Rule (first x_ y_) x) (Rule loop (succ loop)))".parse::<RuleSet>().unwrap();
+//! let expr = "(first a loop)".parse::<Expression>().unwrap();
+//!
+//! let res = expr.replace_with(&rules, Traversal::Outermost, 100).unwrap();
+//! assert_eq!(format!("{:?}", res), "a");
+//!
+//! // `loop` never terminates with strict evaluation
+//! assert!(expr.replace_with(&rules, Traversal::Innermost, 100).is_err());
+//! ```
+//!
+//! More elaborate transformations can be composed from rules with the
+//! combinators in the [`strategy`](strategy/index.html) module.
+//!
+//! Arithmetic on numeric atoms is provided by built-in rules which are added
+//! to a rule set with `push_arithmetic`. They are tried like any other rule
+//! so user rules and arithmetic interleave.
+//!
+//! ```
+//! use ers::{Expression, RuleSet};
+//!
+//! let mut rules = RuleSet::new();
+//! rules.push_arithmetic();
+//!
+//! let expr = "(Times (Plus 1/2 1/3) 6 x)".parse::<Expression>().unwrap();
+//! let res = expr.replace_repeated_rules(&rules, 100).unwrap();
+//!
+//! assert_eq!(format!("{:?}", res), "(Times 5 x)");
+//! ```
 
 #![feature(staged_api)]
 #![staged_api]
@@ -119,10 +301,39 @@
 #![crate_type = "dylib"]
 
 pub use expression::Expression;
+pub use expression::RewriteError;
+pub use expression::REPLACEMENT_LIMIT;
+pub use expression::Subexpressions;
 pub use matching::Match;
+pub use matching::Matches;
 pub use binding::Binding;
 pub use binding::Bind;
+pub use context::Context;
+pub use context::Attribute;
+pub use rule::Rule;
+pub use rule::RuleSet;
+pub use rule::RuleError;
+pub use rule::Rhs;
+pub use number::Number;
+pub use number::BigInt;
+pub use strategy::Strategy;
+pub use traversal::Traversal;
+pub use level::LevelSpec;
+pub use trace::RewriteStep;
+pub use trace::Derivation;
+pub use expression::Parser;
+pub use expression::ParserError;
+pub use expression::ErrorCode;
+pub use expression::Position;
 
 mod expression;
 mod matching;
 mod binding;
+mod context;
+mod rule;
+mod number;
+mod arithmetic;
+pub mod strategy;
+mod traversal;
+mod level;
+mod trace;
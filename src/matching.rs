@@ -13,9 +13,11 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 
 use binding::Binding;
+use context::{Attribute, Context};
 use expression::Expression;
 
 // TODO: rewrite Match trait to allow implementing match_epression and
@@ -26,6 +28,17 @@ pub trait Match {
     // TODO: fix after rewriting
     /// The pattern matching function returns the bindings.
     fn match_pattern<'a>(&'a self, p: &Expression) -> Option<HashMap<String, Binding<'a>>>;
+
+    /// Returns an iterator over all distinct bindings for which the pattern
+    /// matches.
+    fn match_all<'a, 'p>(&'a self, p: &'p Expression) -> Matches<'a, 'p>;
+
+    /// Same as `match_pattern` but evaluates pattern tests in the given
+    /// context.
+    fn match_pattern_in<'a>(&'a self, p: &Expression, ctx: &Context) -> Option<HashMap<String, Binding<'a>>>;
+
+    /// Same as `match_all` but evaluates pattern tests in the given context.
+    fn match_all_in<'a, 'p>(&'a self, p: &'p Expression, ctx: &'p Context) -> Matches<'a, 'p>;
 }
 
 // TODO: rewrite match_expression and match_seq as impl of the Match trait
@@ -44,97 +57,1088 @@ impl Match for Expression {
     /// expr.match_pattern(&pattern); // => Some(HashMap {"a": Expression((y z))})
     /// ```
     fn match_pattern<'a>(&'a self, p: &Expression) -> Option<HashMap<String, Binding<'a>>> {
-        let mut bs: HashMap<String, Binding> = HashMap::new();
-        if match_expression(self, p, &mut bs) {
-            Some(bs)
-        } else {
-            None
+        self.match_all(p).next()
+    }
+
+    /// Returns an iterator yielding every distinct `HashMap<String, Binding>`
+    /// for which the expression matches the pattern. Matches are computed
+    /// lazily in the order in which `match_pattern` would try them.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ers::{Expression, Match};
+    ///
+    /// let expr = "(x y z)".parse::<Expression>().unwrap();
+    /// let pattern = "(__ a_ ___)".parse::<Expression>().unwrap();
+    ///
+    /// // binds `a` to `y` and `z`
+    /// assert_eq!(expr.match_all(&pattern).count(), 2);
+    /// ```
+    fn match_all<'a, 'p>(&'a self, p: &'p Expression) -> Matches<'a, 'p> {
+        Matches::new(self, p, None)
+    }
+
+    fn match_pattern_in<'a>(&'a self, p: &Expression, ctx: &Context) -> Option<HashMap<String, Binding<'a>>> {
+        self.match_all_in(p, ctx).next()
+    }
+
+    fn match_all_in<'a, 'p>(&'a self, p: &'p Expression, ctx: &'p Context) -> Matches<'a, 'p> {
+        Matches::new(self, p, Some(ctx))
+    }
+}
+
+/// An iterator over all distinct bindings of a pattern match.
+///
+/// This struct is created by the `match_all` method on `Match`.
+#[unstable(feature = "ers1")]
+pub struct Matches<'a, 'p> {
+    stack: Vec<State<'a, 'p>>,
+    // different choices can lead to the same bindings, e.g. the splits of
+    // `(__ __)`, which we only want to report once
+    seen: HashSet<Vec<(String, Expression)>>,
+    // all pattern variables of the pattern
+    names: Vec<&'p String>,
+    ctx: Option<&'p Context>,
+}
+
+impl<'a, 'p> Matches<'a, 'p> {
+    fn new(e: &'a Expression, p: &'p Expression, ctx: Option<&'p Context>) -> Matches<'a, 'p> {
+        Matches {
+            stack: vec![State {
+                bs: HashMap::new(),
+                goals: vec![Goal::Expression(e, p)],
+            }],
+            seen: HashSet::new(),
+            names: pattern_names(p),
+            ctx: ctx,
         }
     }
 }
 
-fn match_expression<'a>(e: &'a Expression, p: &Expression, bs: &mut HashMap<String, Binding<'a>>) -> bool {
-    match (e, p) {
-        (_, &Expression::Blank) => { true }
-        (_, &Expression::BlankSeq) => { true }
-        (_, &Expression::BlankNullSeq) => { true }
-        (exp, &Expression::Pattern(ref s)) => {
-            bs.insert(s.clone(), Binding::Expression(exp));
-            true
+impl<'a, 'p> Iterator for Matches<'a, 'p> {
+    type Item = HashMap<String, Binding<'a>>;
+
+    fn next(&mut self) -> Option<HashMap<String, Binding<'a>>> {
+        while let Some(state) = self.stack.pop() {
+            if let Some(mut bs) = step(state, &mut self.stack, self.ctx) {
+                // Variables which only occur in alternatives that were not
+                // taken are bound to the empty sequence.
+                for s in self.names.iter() {
+                    if !bs.contains_key(*s) {
+                        bs.insert((*s).clone(), Binding::Sequence(Cow::Borrowed(&[])));
+                    }
+                }
+
+                if self.seen.insert(canonical(&bs)) {
+                    return Some(bs);
+                }
+            }
         }
-        (&Expression::Atom(ref i), &Expression::Atom(ref j)) => {
-            i == j
+        None
+    }
+}
+
+// The matcher does not recurse directly. Instead every partial match is kept
+// as a `State` on an explicit stack so that we can always backtrack into an
+// earlier choice (e.g. the split of a sequence pattern) if some later part
+// of the pattern does not match.
+#[derive(Clone)]
+enum Goal<'a, 'p> {
+    // match a single expression against a pattern
+    Expression(&'a Expression, &'p Expression),
+    // match a sequence of expressions against a sequence of patterns, the
+    // head is given if the expressions are the arguments of a flat
+    // expression
+    Sequence(&'a [Expression], &'p [Expression], Option<&'a Expression>),
+    // match a sequence of expressions against a pattern followed by a
+    // sequence of patterns
+    Prefix(&'a [Expression], &'p Expression, &'p [Expression], Option<&'a Expression>),
+    // match the arguments of an orderless expression against the
+    // arguments of the pattern
+    Orderless(Vec<&'a Expression>, Vec<&'p Expression>, Option<&'a Expression>),
+    // try the next subset of the arguments of an orderless expression
+    Subsets(Subsets<'a, 'p>),
+    // try the next grouping of the leading arguments of a flat expression
+    Grouping(Grouping<'a, 'p>),
+    // evaluate the named pattern test for a candidate binding
+    Test(&'p str, Binding<'a>),
+}
+
+// The subsets of the arguments of an orderless expression which a pattern
+// can match. They are enumerated lazily, smallest first, since the number
+// of subsets grows exponentially with the number of arguments.
+#[derive(Clone)]
+struct Subsets<'a, 'p> {
+    es: Vec<&'a Expression>,
+    // the indices of the arguments the pattern can match
+    candidates: Vec<usize>,
+    // the subset to try next as indices into `candidates`
+    subset: Vec<usize>,
+    p: &'p Expression,
+    rest: Vec<&'p Expression>,
+    flat: Option<&'a Expression>,
+    // the head the arguments are grouped under if `p` is a single pattern,
+    // otherwise `p` is a sequence pattern bound to the arguments
+    group: Option<&'a Expression>,
+}
+
+// The groupings of the leading arguments of a flat expression under its
+// head which a single pattern can match. Like subsets they are built lazily,
+// the smallest first.
+#[derive(Clone)]
+struct Grouping<'a, 'p> {
+    es: &'a [Expression],
+    // the number of leading arguments to group next
+    n: usize,
+    p: &'p Expression,
+    rest: &'p [Expression],
+    head: &'a Expression,
+}
+
+#[derive(Clone)]
+struct State<'a, 'p> {
+    bs: HashMap<String, Binding<'a>>,
+    // goals still to be matched, the last one is processed next
+    goals: Vec<Goal<'a, 'p>>,
+}
+
+// Processes the next goal of a state and pushes the resulting states onto
+// the stack. Returns the bindings if there is no goal left.
+fn step<'a, 'p>(mut st: State<'a, 'p>, stack: &mut Vec<State<'a, 'p>>,
+                ctx: Option<&'p Context>) -> Option<HashMap<String, Binding<'a>>> {
+    match st.goals.pop() {
+        None => { return Some(st.bs); }
+        Some(Goal::Expression(e, p)) => {
+            match_expression(e, p, st, stack, ctx);
+        }
+        Some(Goal::Sequence(es, ps, flat)) => {
+            match_seq(es, ps, flat, st, stack, ctx);
+        }
+        Some(Goal::Prefix(es, p, ps, flat)) => {
+            match_prefix(es, p, ps, flat, st, stack, ctx);
+        }
+        Some(Goal::Orderless(es, ps, flat)) => {
+            match_orderless(es, ps, flat, st, stack, ctx);
+        }
+        Some(Goal::Subsets(s)) => {
+            match_subsets(s, st, stack, ctx);
+        }
+        Some(Goal::Grouping(g)) => {
+            match_grouping(g, st, stack, ctx);
+        }
+        Some(Goal::Test(t, b)) => {
+            if ctx.map_or(false, |ctx| ctx.test(t, &b)) {
+                stack.push(st);
+            }
+        }
+    }
+    None
+}
+
+fn match_expression<'a, 'p>(e: &'a Expression, p: &'p Expression, mut st: State<'a, 'p>,
+                            stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
+    if let Expression::Alternatives(ref ps) = *p {
+        // push the last alternative first so that they are tried in order
+        for p in ps.iter().rev() {
+            let mut next = st.clone();
+            next.goals.push(Goal::Expression(e, p));
+            stack.push(next);
+        }
+        return;
+    }
+
+    let matched = match (e, p) {
+        (exp, &Expression::Blank(ref h)) => { has_head(exp, h) }
+        (exp, &Expression::BlankSeq(ref h)) => { has_head(exp, h) }
+        (exp, &Expression::BlankNullSeq(ref h)) => { has_head(exp, h) }
+        (exp, &Expression::Pattern(ref s, ref h)) => {
+            has_head(exp, h) && bind(s, Binding::Expression(Cow::Borrowed(exp)), &mut st.bs)
+        }
+        (exp, pat) if is_leaf(pat) => {
+            equivalent(exp, pat)
+        }
+        (&Expression::Sequence(ref es), &Expression::Sequence(ref ps)) => {
+            st.goals.push(Goal::Sequence(es, ps, None));
+            true
         }
         (&Expression::List(ref es), &Expression::List(ref ps)) => {
-            match_seq(es, ps, bs)
+            if es.len() > 0 && ps.len() > 0 && sequence_pattern(&ps[0]).is_none() {
+                // the attributes of the head determine how the arguments
+                // are matched
+                let h = &es[0];
+                let flat = if has_attribute(h, Attribute::Flat, ctx) { Some(h) } else { None };
+
+                if has_attribute(h, Attribute::Orderless, ctx) {
+                    let mut args: Vec<&'p Expression> = ps[1..].iter().collect();
+                    // the patterns which are the most restrictive go first
+                    args.sort_by_key(|p| rank(p));
+
+                    st.goals.push(Goal::Orderless(es[1..].iter().collect(), args, flat));
+                } else {
+                    st.goals.push(Goal::Sequence(&es[1..], &ps[1..], flat));
+                }
+                st.goals.push(Goal::Expression(h, &ps[0]));
+            } else {
+                st.goals.push(Goal::Sequence(es, ps, None));
+            }
+            true
+        }
+        (exp, &Expression::Optional(ref p, _)) => {
+            st.goals.push(Goal::Expression(exp, p));
+            true
+        }
+        (exp, &Expression::PatternTest(ref p, ref t)) => {
+            // the test is only evaluated once the pattern itself matched
+            st.goals.push(Goal::Test(t, Binding::Expression(Cow::Borrowed(exp))));
+            st.goals.push(Goal::Expression(exp, p));
+            true
         }
         _ => { false } // catch all - should not happen
+    };
+
+    if matched {
+        stack.push(st);
     }
 }
 
-fn match_seq<'a>(es: &'a [Expression], ps: &[Expression], bs: &mut HashMap<String, Binding<'a>>) -> bool {
+fn match_seq<'a, 'p>(es: &'a [Expression], ps: &'p [Expression], flat: Option<&'a Expression>,
+                     st: State<'a, 'p>, stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
     if ps.len() == 0 {
-        return es.len() == 0;
+        if es.len() == 0 {
+            stack.push(st);
+        }
+        return;
     }
 
-    match ps[0] {
-        Expression::BlankSeq => {
-            for i in (1..es.len() + 1) {
-                if match_seq(&es[i..], &ps[1..], bs) {
-                    return true;
-                }
-            }
-            false
+    match_prefix(es, &ps[0], &ps[1..], flat, st, stack, ctx);
+}
+
+fn match_prefix<'a, 'p>(es: &'a [Expression], p: &'p Expression, ps: &'p [Expression],
+                        flat: Option<&'a Expression>, mut st: State<'a, 'p>,
+                        stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
+    if let Expression::Alternatives(ref alts) = *p {
+        // alternatives may contain sequence patterns, so each of them has
+        // to be matched against the whole sequence
+        for alt in alts.iter().rev() {
+            let mut next = st.clone();
+            next.goals.push(Goal::Prefix(es, alt, ps, flat));
+            stack.push(next);
         }
-        Expression::BlankNullSeq => {
+        return;
+    }
+
+    if let Expression::Optional(ref q, ref d) = *p {
+        // if the optional pattern is absent the default is matched against
+        // it and we continue with the remaining patterns
+        let mut absent = st.clone();
+        absent.goals.push(Goal::Sequence(es, ps, flat));
+        match_owned((**d).clone(), q, absent, stack, ctx);
+
+        // but we try to match it first
+        if es.len() > 0 {
+            st.goals.push(Goal::Prefix(es, q, ps, flat));
+            stack.push(st);
+        }
+        return;
+    }
+
+    let sp = match sequence_pattern(p) {
+        Some(sp) => sp,
+        None => {
             if es.len() == 0 {
-                return true;
+                return;
             }
 
-            for i in (0..es.len() + 1) {
-                if match_seq(&es[i..], &ps[1..], bs) {
-                    return true;
+            if let Some(h) = flat {
+                // a single pattern can match several arguments of a flat
+                // expression which are grouped under its head
+                if es.len() > 1 {
+                    let mut later = st.clone();
+                    later.goals.push(Goal::Grouping(Grouping {
+                        es: es,
+                        n: 2,
+                        p: p,
+                        rest: ps,
+                        head: h,
+                    }));
+                    stack.push(later);
                 }
             }
-            false
-        }
-        Expression::PatternSeq(ref s) => {
-            for i in (1..es.len() + 1) {
-                let mut h: HashMap<String, Binding<'a>> = HashMap::new();
-                h.insert(s.clone(), Binding::Sequence(&es[0..i]));
-                if match_seq(&es[i..], &ps[1..], &mut h) {
-                    for (key, val) in h.iter() {
-                        bs.insert(key.clone(), val.clone());
-                    }
-                    return true;
+
+            st.goals.push(Goal::Sequence(&es[1..], ps, flat));
+            st.goals.push(Goal::Expression(&es[0], p));
+            stack.push(st);
+            return;
+        }
+    };
+
+    // every element of the sequence has to have the head of the pattern
+    let max = es.iter().take_while(|e| has_head(e, sp.head)).count();
+
+    // push the longest split first so that the shortest one is tried first
+    for i in (sp.min..max + 1).rev() {
+        let mut next = st.clone();
+        next.goals.push(Goal::Sequence(&es[i..], ps, flat));
+        if sp.bind(Cow::Borrowed(&es[0..i]), &mut next) {
+            stack.push(next);
+        }
+    }
+}
+
+// Tries the current grouping of a `Grouping` goal after queueing the next
+// larger one.
+fn match_grouping<'a, 'p>(g: Grouping<'a, 'p>, mut st: State<'a, 'p>,
+                          stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
+    if g.n < g.es.len() {
+        let mut later = st.clone();
+        later.goals.push(Goal::Grouping(Grouping { n: g.n + 1, ..g.clone() }));
+        stack.push(later);
+    }
+
+    st.goals.push(Goal::Sequence(&g.es[g.n..], g.rest, Some(g.head)));
+    match_owned(grouped(g.head, g.es[0..g.n].iter()), g.p, st, stack, ctx);
+}
+
+// Matches the arguments of an orderless expression. Every pattern picks the
+// arguments it matches from the remaining ones, so an argument that does
+// not match cuts off all assignments containing it.
+fn match_orderless<'a, 'p>(es: Vec<&'a Expression>, ps: Vec<&'p Expression>,
+                           flat: Option<&'a Expression>, st: State<'a, 'p>,
+                           stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
+    if ps.len() == 0 {
+        if es.len() == 0 {
+            stack.push(st);
+        }
+        return;
+    }
+
+    // give up early if the number of arguments can not fit the patterns
+    let mut required = 0;
+    let mut unbounded = flat.is_some();
+    for p in ps.iter() {
+        match sequence_pattern(p) {
+            Some(sp) => {
+                required += sp.min;
+                unbounded = true;
+            }
+            None => {
+                if let Expression::Optional(..) = **p {} else {
+                    required += 1;
                 }
             }
-            false
         }
-        Expression::PatternNullSeq(ref s) => {
-            if es.len() == 0 {
-                bs.insert(s.clone(), Binding::Sequence(es));
-                return true;
+    }
+    if es.len() < required || (!unbounded && es.len() > ps.len()) {
+        return;
+    }
+
+    let p = ps[0];
+    let rest = ps[1..].to_vec();
+
+    if let Some(sp) = sequence_pattern(p) {
+        // every element of the sequence has to have the head of the pattern
+        let candidates: Vec<usize> = (0..es.len()).filter(|&i| has_head(es[i], sp.head)).collect();
+
+        if rest.len() == 0 {
+            // the last pattern has to take all remaining arguments
+            let mut st = st;
+            if candidates.len() == es.len() && es.len() >= sp.min {
+                let seq: Vec<Expression> = es.iter().map(|&e| e.clone()).collect();
+                if sp.bind(Cow::Owned(seq), &mut st) {
+                    stack.push(st);
+                }
             }
+            return;
+        }
 
-            for i in (0..es.len() + 1) {
-                let mut h: HashMap<String, Binding<'a>> = HashMap::new();
-                h.insert(s.clone(), Binding::Sequence(&es[0..i]));
-                if match_seq(&es[i..], &ps[1..], &mut h) {
-                    for (key, val) in h.iter() {
-                        bs.insert(key.clone(), val.clone());
-                    }
-                    return true;
+        if sp.min <= candidates.len() {
+            let mut next = st;
+            next.goals.push(Goal::Subsets(Subsets {
+                es: es,
+                candidates: candidates,
+                subset: (0..sp.min).collect(),
+                p: p,
+                rest: rest,
+                flat: flat,
+                group: None,
+            }));
+            stack.push(next);
+        }
+        return;
+    }
+
+    let p = match *p {
+        Expression::Optional(ref q, ref d) => {
+            // pushed first so that it is only tried after all arguments
+            let mut absent = st.clone();
+            absent.goals.push(Goal::Orderless(es.clone(), rest.clone(), flat));
+            match_owned((**d).clone(), q, absent, stack, ctx);
+            &**q
+        }
+        _ => p
+    };
+
+    if let Some(h) = flat {
+        // a single pattern can match any subset of the arguments of a flat
+        // expression which is grouped under its head
+        if rest.len() == 0 {
+            // the last pattern has to take all remaining arguments
+            if es.len() == 1 {
+                let mut st = st;
+                st.goals.push(Goal::Expression(es[0], p));
+                stack.push(st);
+            } else if es.len() > 1 {
+                match_owned(grouped(h, es.into_iter()), p, st, stack, ctx);
+            }
+            return;
+        }
+
+        // the groups are only tried after all single arguments
+        if es.len() >= 2 {
+            let mut next = st.clone();
+            next.goals.push(Goal::Subsets(Subsets {
+                es: es.clone(),
+                candidates: (0..es.len()).collect(),
+                subset: vec![0, 1],
+                p: p,
+                rest: rest.clone(),
+                flat: flat,
+                group: Some(h),
+            }));
+            stack.push(next);
+        }
+    }
+
+    for j in (0..es.len()).rev() {
+        // equal arguments lead to the same matches
+        if es[..j].iter().any(|e| *e == es[j]) {
+            continue;
+        }
+
+        let mut remaining = es.clone();
+        remaining.remove(j);
+
+        let mut next = st.clone();
+        next.goals.push(Goal::Orderless(remaining, rest.clone(), flat));
+        next.goals.push(Goal::Expression(es[j], p));
+        stack.push(next);
+    }
+}
+
+// Tries the current subset of a `Subsets` goal after queueing the next one.
+fn match_subsets<'a, 'p>(s: Subsets<'a, 'p>, mut st: State<'a, 'p>,
+                         stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
+    if let Some(subset) = next_subset(&s.subset, s.candidates.len()) {
+        let mut later = st.clone();
+        later.goals.push(Goal::Subsets(Subsets { subset: subset, ..s.clone() }));
+        stack.push(later);
+    }
+
+    let es = &s.es;
+    let chosen: Vec<usize> = s.subset.iter().map(|&i| s.candidates[i]).collect();
+    let remaining: Vec<&'a Expression> = (0..es.len())
+        .filter(|i| !chosen.contains(i))
+        .map(|i| es[i])
+        .collect();
+    st.goals.push(Goal::Orderless(remaining, s.rest, s.flat));
+
+    match s.group {
+        Some(h) => {
+            match_owned(grouped(h, chosen.iter().map(|&i| es[i])), s.p, st, stack, ctx);
+        }
+        None => {
+            if let Some(sp) = sequence_pattern(s.p) {
+                let seq: Vec<Expression> = chosen.iter().map(|&i| es[i].clone()).collect();
+                if sp.bind(Cow::Owned(seq), &mut st) {
+                    stack.push(st);
                 }
             }
-            false
         }
-        _ => {
-            if es.len() == 0 {
-                return false;
+    }
+}
+
+// Returns the subset of `0..n` following `c` in lexicographic order, or the
+// first larger subset if `c` is the last one of its size.
+fn next_subset(c: &[usize], n: usize) -> Option<Vec<usize>> {
+    let k = c.len();
+    for i in (0..k).rev() {
+        if c[i] < n - k + i {
+            let mut next = c[..i].to_vec();
+            next.extend(c[i] + 1..c[i] + 1 + k - i);
+            return Some(next);
+        }
+    }
+    if k < n {
+        Some((0..k + 1).collect())
+    } else {
+        None
+    }
+}
+
+// The bindings sorted by name with sequences as `Sequence` expressions, so
+// that equal bindings are equal and hash alike.
+fn canonical(bs: &HashMap<String, Binding>) -> Vec<(String, Expression)> {
+    let mut v: Vec<(String, Expression)> = bs.iter().map(|(s, b)| {
+        let e = match *b {
+            Binding::Expression(ref e) => (**e).clone(),
+            Binding::Sequence(ref es) => Expression::Sequence(es.to_vec()),
+        };
+        (s.clone(), e)
+    }).collect();
+    v.sort_by(|x, y| x.0.cmp(&y.0));
+    v
+}
+
+// Matches an expression which is not part of the matched expression, like
+// the grouped arguments of a flat expression. As the expression only lives
+// during this call all new bindings are owned.
+fn match_owned<'a, 'p>(e: Expression, p: &'p Expression, st: State<'a, 'p>,
+                       stack: &mut Vec<State<'a, 'p>>, ctx: Option<&'p Context>) {
+    let found: Vec<Vec<(String, Binding<'static>)>> = {
+        let inner = Matches {
+            stack: vec![State {
+                bs: st.bs.clone(),
+                goals: vec![Goal::Expression(&e, p)],
+            }],
+            seen: HashSet::new(),
+            names: Vec::new(),
+            ctx: ctx,
+        };
+
+        inner.map(|bs| {
+            bs.into_iter()
+              .filter(|&(ref s, _)| !st.bs.contains_key(s))
+              .map(|(s, b)| (s, b.into_owned()))
+              .collect()
+        }).collect()
+    };
+
+    for bs in found.into_iter().rev() {
+        let mut next = st.clone();
+        next.bs.extend(bs);
+        stack.push(next);
+    }
+}
+
+// Builds the expression `(h es...)`.
+fn grouped<'a, I>(h: &Expression, es: I) -> Expression where I: Iterator<Item=&'a Expression> {
+    let mut v = vec![h.clone()];
+    v.extend(es.cloned());
+    Expression::List(v)
+}
+
+// A sequence pattern with its minimal length, name, head and the tests which
+// have to be checked for every candidate sequence.
+struct SequencePattern<'p> {
+    min: usize,
+    name: Option<&'p String>,
+    head: &'p Option<String>,
+    tests: Vec<&'p str>,
+}
+
+impl<'p> SequencePattern<'p> {
+    // Binds a candidate sequence and queues the tests for it. Returns false
+    // if the binding is inconsistent with the existing ones.
+    fn bind<'a>(&self, seq: Cow<'a, [Expression]>, st: &mut State<'a, 'p>) -> bool {
+        for t in self.tests.iter().rev() {
+            st.goals.push(Goal::Test(t, Binding::Sequence(seq.clone())));
+        }
+        match self.name {
+            Some(s) => bind(s, Binding::Sequence(seq), &mut st.bs),
+            None => true
+        }
+    }
+}
+
+fn sequence_pattern(p: &Expression) -> Option<SequencePattern> {
+    let mut p = p;
+    let mut tests = Vec::new();
+    while let Expression::PatternTest(ref q, ref t) = *p {
+        tests.push(&t[..]);
+        p = q;
+    }
+
+    let (min, name, head) = match *p {
+        Expression::BlankSeq(ref h) => (1, None, h),
+        Expression::BlankNullSeq(ref h) => (0, None, h),
+        Expression::PatternSeq(ref s, ref h) => (1, Some(s), h),
+        Expression::PatternNullSeq(ref s, ref h) => (0, Some(s), h),
+        _ => { return None; }
+    };
+
+    Some(SequencePattern { min: min, name: name, head: head, tests: tests })
+}
+
+// Orders the arguments of an orderless pattern: literals, single patterns,
+// optional patterns and finally sequence patterns.
+fn rank(p: &Expression) -> usize {
+    if is_literal(p) {
+        0
+    } else if sequence_pattern(p).is_some() {
+        3
+    } else if let Expression::Optional(..) = *p {
+        2
+    } else {
+        1
+    }
+}
+
+fn is_leaf(e: &Expression) -> bool {
+    match *e {
+        Expression::Atom(_) |
+        Expression::Integer(_) |
+        Expression::BigInteger(_) |
+        Expression::Rational(..) |
+        Expression::Float(_) |
+        Expression::String(_) => true,
+        _ => false
+    }
+}
+
+fn is_literal(p: &Expression) -> bool {
+    match *p {
+        ref e if is_leaf(e) => true,
+        Expression::List(ref ps) | Expression::Sequence(ref ps) => ps.iter().all(is_literal),
+        _ => false
+    }
+}
+
+fn has_attribute(head: &Expression, a: Attribute, ctx: Option<&Context>) -> bool {
+    match (head, ctx) {
+        (&Expression::Atom(ref h), Some(ctx)) => ctx.has_attribute(h, a),
+        _ => false
+    }
+}
+
+// Collects the names of all pattern variables occurring in a pattern.
+fn pattern_names(p: &Expression) -> Vec<&String> {
+    let mut names = Vec::new();
+    collect_names(p, &mut names);
+    names
+}
+
+fn collect_names<'p>(p: &'p Expression, names: &mut Vec<&'p String>) {
+    match *p {
+        Expression::Pattern(ref s, _) |
+        Expression::PatternSeq(ref s, _) |
+        Expression::PatternNullSeq(ref s, _) => {
+            if !names.contains(&s) {
+                names.push(s);
+            }
+        }
+        Expression::PatternTest(ref p, _) => collect_names(p, names),
+        Expression::Optional(ref p, _) => collect_names(p, names),
+        Expression::List(ref ps) |
+        Expression::Sequence(ref ps) |
+        Expression::Alternatives(ref ps) => {
+            for p in ps {
+                collect_names(p, names);
             }
+        }
+        _ => {}
+    }
+}
+
+// Checks if the expression has the head required by a blank or pattern.
+fn has_head(e: &Expression, h: &Option<String>) -> bool {
+    let h = match *h {
+        Some(ref h) => h,
+        None => { return true; }
+    };
 
-            match_expression(&es[0], &ps[0], bs) && match_seq(&es[1..], &ps[1..], bs)
+    match (e, &h[..]) {
+        (e, "Atom") => is_leaf(e),
+        (&Expression::List(_), "List") => true,
+        (&Expression::Atom(_), "Symbol") => true,
+        (&Expression::Integer(_), "Integer") => true,
+        (&Expression::BigInteger(_), "Integer") => true,
+        (&Expression::Rational(..), "Rational") => true,
+        (&Expression::Float(_), "Float") => true,
+        (&Expression::String(_), "String") => true,
+        (&Expression::Sequence(_), "Sequence") => true,
+        (&Expression::List(ref es), _) => {
+            match es.first() {
+                Some(&Expression::Atom(ref s)) => s == h,
+                _ => false
+            }
         }
+        _ => false
+    }
+}
+
+// Inserts a binding unless the name is already bound. A repeated pattern
+// variable only matches if both bindings are structurally equal.
+fn bind<'a>(s: &String, b: Binding<'a>, bs: &mut HashMap<String, Binding<'a>>) -> bool {
+    if let Some(old) = bs.get(s) {
+        return match (old, &b) {
+            (&Binding::Expression(ref x), &Binding::Expression(ref y)) => equivalent(x, y),
+            (&Binding::Sequence(ref xs), &Binding::Sequence(ref ys)) => {
+                xs.len() == ys.len() && xs.iter().zip(ys.iter()).all(|(x, y)| equivalent(x, y))
+            }
+            _ => false
+        };
+    }
+    bs.insert(s.clone(), b);
+    true
+}
+
+// Equality as seen by the matcher. Unlike `==`, which compares floats by
+// their bits, floats are compared by value so that `-0.0` matches `0.0` and
+// NaN matches nothing.
+fn equivalent(a: &Expression, b: &Expression) -> bool {
+    match (a, b) {
+        (&Expression::Float(x), &Expression::Float(y)) => x == y,
+        (&Expression::List(ref xs), &Expression::List(ref ys)) |
+        (&Expression::Sequence(ref xs), &Expression::Sequence(ref ys)) => {
+            xs.len() == ys.len() && xs.iter().zip(ys.iter()).all(|(x, y)| equivalent(x, y))
+        }
+        _ => a == b
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use binding::Binding;
+    use context::{Attribute, Context};
+    use expression::Expression;
+    use super::Match;
+
+    fn matches(e: &str, p: &str) -> bool {
+        let e = e.parse::<Expression>().unwrap();
+        let p = p.parse::<Expression>().unwrap();
+        e.match_pattern(&p).is_some()
+    }
+
+    #[test]
+    fn repeated_pattern() {
+        assert!(matches("(f a a)", "(f x_ x_)"));
+        assert!(!matches("(f a b)", "(f x_ x_)"));
+        assert!(matches("(f (g a) (g a))", "(f x_ x_)"));
+        assert!(!matches("(f (g a) (g b))", "(f x_ x_)"));
+        assert!(matches("(f a (g a))", "(f x_ (g x_))"));
+    }
+
+    #[test]
+    fn repeated_sequence() {
+        assert!(matches("(f a b a b)", "(f x__ x__)"));
+        assert!(!matches("(f a b a)", "(f x__ x__)"));
+        // the first split binds x to (a) which has to be revised
+        assert!(matches("(f a b c a b)", "(f x__ c x__)"));
+        assert!(matches("(f a b (g a b))", "(f x___ (g x___))"));
+        assert!(!matches("(f (g) c d)", "(f (g x___) c x___)"));
+        // backtracks into the split of the nested list
+        assert!(matches("(f (g a b c) a b)", "(f (g x__ y__) x__)"));
+    }
+
+    #[test]
+    fn head() {
+        assert!(matches("(g (f a) b)", "(g x_f _)"));
+        assert!(!matches("(g (h a) b)", "(g x_f _)"));
+        assert!(!matches("(g f b)", "(g x_f _)"));
+        assert!(matches("(g a (b))", "(g _Atom _List)"));
+        assert!(!matches("(g (a) b)", "(g _Atom _List)"));
+    }
+
+    #[test]
+    fn head_leaf() {
+        assert!(matches("(g 1 \"1\" a)", "(g _Integer _String _Symbol)"));
+        assert!(matches("(g 1/2 0.5 12345678901234567890)", "(g _Rational _Float _Integer)"));
+        assert!(!matches("(g \"1\")", "(g _Integer)"));
+        assert!(!matches("(g 1)", "(g _Symbol)"));
+        assert!(matches("(g 1 \"a\" b)", "(g __Atom)"));
+        assert!(matches("(g 1)", "(g 1)"));
+        assert!(!matches("(g \"1\")", "(g 1)"));
+    }
+
+    #[test]
+    fn head_sequence() {
+        assert!(matches("(g (f) (f a) b)", "(g x__f b)"));
+        assert!(!matches("(g (f) (h a) b)", "(g x__f b)"));
+        assert!(matches("(g a b (c))", "(g __Atom ___List)"));
+        assert!(matches("(g a b)", "(g __Atom ___List)"));
+        assert!(!matches("(g (a) b)", "(g __Atom ___List)"));
+    }
+
+    fn test_context() -> Context {
+        let mut ctx = Context::new();
+        ctx.add_test("number", |b: &Binding| {
+            match b.as_expression() {
+                Some(&Expression::Integer(_)) => true,
+                _ => false
+            }
+        });
+        ctx.add_test("pair", |b: &Binding| {
+            b.as_sequence().map_or(false, |es| es.len() == 2)
+        });
+        ctx
+    }
+
+    fn matches_in(e: &str, p: &str) -> bool {
+        let e = e.parse::<Expression>().unwrap();
+        let p = p.parse::<Expression>().unwrap();
+        e.match_pattern_in(&p, &test_context()).is_some()
+    }
+
+    #[test]
+    fn pattern_test() {
+        assert!(matches_in("(f 1)", "(f x_?number)"));
+        assert!(!matches_in("(f a)", "(f x_?number)"));
+        assert!(!matches_in("(f 1)", "(f x_?unknown)"));
+        // without a context no test succeeds
+        assert!(!matches("(f 1)", "(f x_?number)"));
+    }
+
+    #[test]
+    fn pattern_test_backtracking() {
+        assert!(matches_in("(f a b c)", "(f x__?pair y__)"));
+        assert!(!matches_in("(f a b)", "(f x__?pair y__)"));
+        assert!(matches_in("(f a b 1)", "(f ___ x_?number ___)"));
+        assert!(matches_in("(f 1 b c)", "(f x___ y_?number z___)"));
+    }
+
+    #[test]
+    fn alternatives() {
+        assert!(matches("(Sin a)", "(Sin x_) | (Cos x_)"));
+        assert!(matches("(Cos a)", "(Sin x_) | (Cos x_)"));
+        assert!(!matches("(Tan a)", "(Sin x_) | (Cos x_)"));
+        assert!(matches("(f b)", "(f a | b | c)"));
+        assert!(!matches("(f d)", "(f a | b | c)"));
+    }
+
+    #[test]
+    fn alternatives_sequence() {
+        assert!(matches("(f a b c)", "(f x__ | y_ c)"));
+        assert!(matches("(f c)", "(f a | ___ c)"));
+        // backtracks into the next alternative if the rest does not match
+        assert!(matches("(f a a)", "(f x_ | (g x_) a)"));
+        assert!(matches("(f (g a) a)", "(f (h x_) | (g x_) x_)"));
+    }
+
+    #[test]
+    fn alternatives_unbound() {
+        let expr = "(f a)".parse::<Expression>().unwrap();
+        let pattern = "(f x_ | (g y_))".parse::<Expression>().unwrap();
+        let bs = expr.match_pattern(&pattern).unwrap();
+
+        assert_eq!(bs["y"].as_sequence().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn optional() {
+        let pattern = "(Plus a_ b_:0)".parse::<Expression>().unwrap();
+
+        let expr = "(Plus y z)".parse::<Expression>().unwrap();
+        let bs = expr.match_pattern(&pattern).unwrap();
+        assert_eq!(format!("{:?}", bs["b"].as_expression().unwrap()), "z");
+
+        let expr = "(Plus y)".parse::<Expression>().unwrap();
+        let bs = expr.match_pattern(&pattern).unwrap();
+        assert_eq!(format!("{:?}", bs["a"].as_expression().unwrap()), "y");
+        assert_eq!(format!("{:?}", bs["b"].as_expression().unwrap()), "0");
+
+        assert!(!matches("(Plus)", "(Plus a_ b_:0)"));
+        assert!(!matches("(Plus x y z)", "(Plus a_ b_:0)"));
+    }
+
+    #[test]
+    fn optional_backtracking() {
+        // `a` is only absent if the rest does not match otherwise
+        assert!(matches("(f y)", "(f a_:0 b_)"));
+        assert!(matches("(f 0 0)", "(f a_:0 a_)"));
+        assert!(!matches("(f 1)", "(f a_:0 a_)"));
+        assert!(matches("(f (g 1) 1)", "(f x_g:(g 1) y___)"));
+        assert!(matches("(f 1 2)", "(f x_:(g 1) y___)"));
+    }
+
+    #[test]
+    fn float_equality() {
+        // floats are compared by value, not by representation
+        assert!(matches("(f -0.0)", "(f 0.0)"));
+        assert!(matches("(f 0.0 -0.0)", "(f x_ x_)"));
+        assert!(matches("(g (f 0.0) (f -0.0))", "(g x_ x_)"));
+        assert!(!matches("(f 1.0)", "(f 1)"));
+    }
+
+    #[test]
+    fn optional_default_matched() {
+        // the default is matched against the optional pattern
+        let pattern = "(f (h y_):(h 0))".parse::<Expression>().unwrap();
+        let expr = "(f)".parse::<Expression>().unwrap();
+        let bs = expr.match_pattern(&pattern).unwrap();
+        assert_eq!(format!("{:?}", bs["y"].as_expression().unwrap()), "0");
+
+        assert!(!matches("(f)", "(f x_g:(h 1))"));
+        assert!(matches_in("(f)", "(f x_?number:1)"));
+        assert!(!matches_in("(f)", "(f x_?number:a)"));
+    }
+
+    fn orderless_context() -> Context {
+        let mut ctx = test_context();
+        ctx.set_attribute("Plus", Attribute::Orderless);
+        ctx
+    }
+
+    fn matches_orderless(e: &str, p: &str) -> bool {
+        let e = e.parse::<Expression>().unwrap();
+        let p = p.parse::<Expression>().unwrap();
+        e.match_pattern_in(&p, &orderless_context()).is_some()
+    }
+
+    #[test]
+    fn orderless() {
+        assert!(matches_orderless("(Plus 0 y)", "(Plus x_ 0)"));
+        assert!(matches_orderless("(Plus a b c)", "(Plus c a b)"));
+        assert!(!matches_orderless("(Plus a b c)", "(Plus c a)"));
+        assert!(!matches_orderless("(Plus a b)", "(Plus c a x_)"));
+        assert!(matches_orderless("(Plus (f a) b a)", "(Plus (f x_) b x_)"));
+        assert!(!matches_orderless("(Plus (f a) b b)", "(Plus (f x_) b x_)"));
+        assert!(matches_orderless("(Plus 1 a)", "(Plus x_?number y_)"));
+        assert!(matches_orderless("(Plus y 0)", "(Plus x_ y_:0 0)"));
+        // only heads with the attribute are orderless
+        assert!(!matches_orderless("(Times 0 y)", "(Times x_ 0)"));
+    }
+
+    #[test]
+    fn orderless_sequence() {
+        let ctx = orderless_context();
+        let expr = "(Plus a 0 b)".parse::<Expression>().unwrap();
+        let pattern = "(Plus 0 rest___)".parse::<Expression>().unwrap();
+
+        let bs = expr.match_pattern_in(&pattern, &ctx).unwrap();
+        assert_eq!(format!("{:?}", bs["rest"].as_sequence().unwrap()), "[a, b]");
+
+        assert!(matches_orderless("(Plus a (f 1) b (f 2))", "(Plus x__f y__)"));
+        assert!(!matches_orderless("(Plus a (f 1) b (f 2))", "(Plus x__f y__f)"));
+        assert!(matches_orderless("(Plus a 1 b 2)", "(Plus x__?pair y__?pair)"));
+
+        let pattern = "(Plus x__ y__)".parse::<Expression>().unwrap();
+        let expr = "(Plus a b c)".parse::<Expression>().unwrap();
+        assert_eq!(expr.match_all_in(&pattern, &ctx).count(), 6);
+    }
+
+    #[test]
+    fn orderless_sequence_many() {
+        // the subsets of the arguments must not all be built up front
+        let ctx = orderless_context();
+        let args: Vec<String> = (0..20).map(|i| format!("a{}", i)).collect();
+        let expr = format!("(Plus {} 0)", args.join(" ")).parse::<Expression>().unwrap();
+
+        let pattern = "(Plus 0 rest___)".parse::<Expression>().unwrap();
+        let bs = expr.match_pattern_in(&pattern, &ctx).unwrap();
+        assert_eq!(bs["rest"].as_sequence().unwrap().len(), 20);
+
+        let pattern = "(Plus x__ y__)".parse::<Expression>().unwrap();
+        let bs = expr.match_pattern_in(&pattern, &ctx).unwrap();
+        assert_eq!(format!("{:?}", bs["x"].as_sequence().unwrap()), "[a0]");
+        assert_eq!(bs["y"].as_sequence().unwrap().len(), 20);
+
+        let pattern = "(Plus x__ y__ 1)".parse::<Expression>().unwrap();
+        assert!(expr.match_pattern_in(&pattern, &ctx).is_none());
+    }
+
+    fn flat_context(orderless: bool) -> Context {
+        let mut ctx = test_context();
+        ctx.set_attribute("Plus", Attribute::Flat);
+        if orderless {
+            ctx.set_attribute("Plus", Attribute::Orderless);
+        }
+        ctx
+    }
+
+    fn match_flat(e: &str, p: &str, orderless: bool) -> Option<String> {
+        let e = e.parse::<Expression>().unwrap();
+        let p = p.parse::<Expression>().unwrap();
+        let ctx = flat_context(orderless);
+        let bs = e.match_pattern_in(&p, &ctx);
+        bs.map(|bs| {
+            let mut v: Vec<String> = bs.iter().map(|(k, b)| format!("{}: {:?}", k, b)).collect();
+            v.sort();
+            v.join(", ")
+        })
+    }
+
+    #[test]
+    fn flat() {
+        assert_eq!(match_flat("(Plus x y z)", "(Plus a_ b_)", false).unwrap(),
+                   "a: Expression(x), b: Expression((Plus y z))");
+        assert_eq!(match_flat("(Plus x y z)", "(Plus a_ z)", false).unwrap(),
+                   "a: Expression((Plus x y))");
+        assert_eq!(match_flat("(Plus x y z)", "(Plus a_ (Plus y z))", false).unwrap(),
+                   "a: Expression(x)");
+        assert_eq!(match_flat("(Plus x y x y)", "(Plus a_ a_)", false).unwrap(),
+                   "a: Expression((Plus x y))");
+        assert!(match_flat("(Plus x y z)", "(Plus a_ y)", false).is_none());
+        assert!(match_flat("(Times x y z)", "(Times a_ b_)", false).is_none());
+    }
+
+    #[test]
+    fn flat_sequence() {
+        // sequence patterns still bind sequences
+        assert_eq!(match_flat("(Plus x y z)", "(Plus a_ b__)", false).unwrap(),
+                   "a: Expression(x), b: Sequence([y, z])");
+        assert_eq!(match_flat("(Plus w x y z)", "(Plus a__ b_ z)", false).unwrap(),
+                   "a: Sequence([w]), b: Expression((Plus x y))");
+    }
+
+    #[test]
+    fn flat_many() {
+        // the groupings of the arguments are only built when they are tried
+        let args: Vec<String> = (0..20).map(|i| format!("a{}", i)).collect();
+        let e = format!("(Plus {})", args.join(" "));
+        assert_eq!(match_flat(&e, "(Plus x_ y_ z_)", false).unwrap(),
+                   format!("x: Expression(a0), y: Expression(a1), z: Expression((Plus {}))",
+                           args[2..].join(" ")));
+        assert_eq!(match_flat(&e, "(Plus x_ a19)", false).unwrap(),
+                   format!("x: Expression((Plus {}))", args[..19].join(" ")));
+    }
+
+    #[test]
+    fn flat_orderless() {
+        assert_eq!(match_flat("(Plus x y z)", "(Plus a_ y)", true).unwrap(),
+                   "a: Expression((Plus x z))");
+        assert_eq!(match_flat("(Plus x 1 y 2)", "(Plus a_ b_?number)", true).unwrap(),
+                   "a: Expression((Plus x 1 y)), b: Expression(2)");
+        assert!(match_flat("(Plus x y z)", "(Plus a_ w)", true).is_none());
+
+        // the groupings of the arguments must not all be tried up front
+        let args: Vec<String> = (0..20).map(|i| format!("a{}", i)).collect();
+        let e = format!("(Plus {})", args.join(" "));
+        assert_eq!(match_flat(&e, "(Plus x_ y_)", true).unwrap(),
+                   format!("x: Expression(a0), y: Expression((Plus {}))", args[1..].join(" ")));
+        assert_eq!(match_flat(&e, "(Plus x_ a7 y_)", true).unwrap(),
+                   format!("x: Expression(a0), y: Expression((Plus {} {}))",
+                           args[1..7].join(" "), args[8..].join(" ")));
+    }
+
+    #[test]
+    fn match_all() {
+        let expr = "(a b c)".parse::<Expression>().unwrap();
+        let pattern = "(x___ y___)".parse::<Expression>().unwrap();
+
+        let splits: Vec<_> = expr.match_all(&pattern).map(|bs| {
+            (bs["x"].as_sequence().unwrap().len(), bs["y"].as_sequence().unwrap().len())
+        }).collect();
+
+        assert_eq!(splits, vec![(0, 3), (1, 2), (2, 1), (3, 0)]);
+    }
+
+    #[test]
+    fn match_all_distinct() {
+        let expr = "(a b c)".parse::<Expression>().unwrap();
+        let pattern = "(__ __)".parse::<Expression>().unwrap();
+        assert_eq!(expr.match_all(&pattern).count(), 1);
+
+        let pattern = "(x_ y_)".parse::<Expression>().unwrap();
+        assert_eq!(expr.match_all(&pattern).count(), 0);
+
+        let expr = "(f a a a a a a a a a a a a)".parse::<Expression>().unwrap();
+        let pattern = "(f ___ x_ ___)".parse::<Expression>().unwrap();
+        assert_eq!(expr.match_all(&pattern).count(), 1);
+
+        // a single expression differs from a sequence of one
+        let expr = "(f a)".parse::<Expression>().unwrap();
+        let pattern = "(f x_ | x__)".parse::<Expression>().unwrap();
+        assert_eq!(expr.match_all(&pattern).count(), 2);
     }
 }
@@ -0,0 +1,766 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::ops::{Add, Mul, Neg, Sub};
+use std::str::FromStr;
+
+use expression::Expression;
+
+// every digit holds nine decimal digits which keeps printing simple
+const BASE: u64 = 1_000_000_000;
+
+// exact powers with more decimal digits are not evaluated as computing
+// them would take too long
+const MAX_POWER_DIGITS: f64 = 10_000.0;
+
+/// An integer of arbitrary size.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+#[unstable(feature = "ers1")]
+pub struct BigInt {
+    negative: bool,
+    // least significant digit first, no leading zeros
+    digits: Vec<u32>,
+}
+
+#[unstable(feature = "ers1")]
+impl BigInt {
+    /// Returns zero.
+    #[unstable(feature = "ers1")]
+    pub fn zero() -> BigInt {
+        BigInt { negative: false, digits: Vec::new() }
+    }
+
+    /// Converts a machine integer.
+    #[unstable(feature = "ers1")]
+    pub fn from_i64(n: i64) -> BigInt {
+        let mut m = n.wrapping_abs() as u64;
+        let mut digits = Vec::new();
+        while m > 0 {
+            digits.push((m % BASE) as u32);
+            m /= BASE;
+        }
+        BigInt { negative: n < 0, digits: digits }
+    }
+
+    /// Returns the value if it fits into an `i64`.
+    #[unstable(feature = "ers1")]
+    pub fn to_i64(&self) -> Option<i64> {
+        let mut m: u64 = 0;
+        for d in self.digits.iter().rev() {
+            m = match m.checked_mul(BASE).and_then(|m| m.checked_add(*d as u64)) {
+                Some(m) => m,
+                None => return None
+            };
+        }
+        if self.negative {
+            if m > i64::max_value() as u64 + 1 { None } else { Some((m as i64).wrapping_neg()) }
+        } else {
+            if m > i64::max_value() as u64 { None } else { Some(m as i64) }
+        }
+    }
+
+    /// Returns the nearest floating point number.
+    #[unstable(feature = "ers1")]
+    pub fn to_f64(&self) -> f64 {
+        let m = self.digits.iter().rev().fold(0.0, |m, d| m * BASE as f64 + *d as f64);
+        if self.negative { -m } else { m }
+    }
+
+    /// Returns true if the integer is zero.
+    #[unstable(feature = "ers1")]
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    /// Returns true if the integer is less than zero.
+    #[unstable(feature = "ers1")]
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    /// Returns the absolute value.
+    #[unstable(feature = "ers1")]
+    pub fn abs(&self) -> BigInt {
+        BigInt { negative: false, digits: self.digits.clone() }
+    }
+
+    /// Returns quotient and remainder of the division rounding towards
+    /// zero or `None` if the divisor is zero.
+    #[unstable(feature = "ers1")]
+    pub fn div_rem(&self, other: &BigInt) -> Option<(BigInt, BigInt)> {
+        if other.is_zero() {
+            return None;
+        }
+        let (q, r) = div_rem(&self.digits, &other.digits);
+        Some((BigInt::new(self.negative != other.negative, q),
+              BigInt::new(self.negative, r)))
+    }
+
+    /// Returns the greatest common divisor which is never negative.
+    #[unstable(feature = "ers1")]
+    pub fn gcd(&self, other: &BigInt) -> BigInt {
+        let mut a = self.abs();
+        let mut b = other.abs();
+        // divide big integers only while the remainder has more limbs than
+        // fit into a machine word, then finish with machine arithmetic
+        while b.digits.len() > 2 {
+            let (_, r) = a.div_rem(&b).unwrap();
+            a = b;
+            b = r;
+        }
+        if b.is_zero() {
+            return a;
+        }
+        let (mut x, mut y) = (a.div_rem(&b).unwrap().1.to_u64(), b.to_u64());
+        while x != 0 {
+            let r = y % x;
+            y = x;
+            x = r;
+        }
+        BigInt::new(false, vec![(y % BASE) as u32, (y / BASE) as u32])
+    }
+
+    // the absolute value of an integer with at most two limbs
+    fn to_u64(&self) -> u64 {
+        self.digits.iter().rev().fold(0, |m, d| m * BASE + *d as u64)
+    }
+
+    // approximates the number of decimal digits of the absolute value
+    fn log10(&self) -> f64 {
+        match self.digits.last() {
+            Some(&d) => (self.digits.len() - 1) as f64 * 9.0 + (d as f64).log10(),
+            None => 0.0
+        }
+    }
+
+    /// Raises the integer to the power of `exp`.
+    #[unstable(feature = "ers1")]
+    pub fn pow(&self, exp: u32) -> BigInt {
+        let mut res = BigInt::from_i64(1);
+        let mut base = self.clone();
+        let mut exp = exp;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                res = &res * &base;
+            }
+            base = &base * &base;
+            exp >>= 1;
+        }
+        res
+    }
+
+    fn new(negative: bool, mut digits: Vec<u32>) -> BigInt {
+        while digits.last() == Some(&0) {
+            digits.pop();
+        }
+        BigInt { negative: negative && !digits.is_empty(), digits: digits }
+    }
+}
+
+fn cmp_digits(a: &[u32], b: &[u32]) -> Ordering {
+    if a.len() != b.len() {
+        return a.len().cmp(&b.len());
+    }
+    for (x, y) in a.iter().rev().zip(b.iter().rev()) {
+        if x != y {
+            return x.cmp(y);
+        }
+    }
+    Ordering::Equal
+}
+
+fn add_digits(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut v = Vec::new();
+    let mut carry = 0;
+    for i in 0..a.len().max(b.len()) {
+        let s = *a.get(i).unwrap_or(&0) as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        v.push((s % BASE) as u32);
+        carry = s / BASE;
+    }
+    if carry > 0 {
+        v.push(carry as u32);
+    }
+    v
+}
+
+// requires a >= b
+fn sub_digits(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut v = Vec::new();
+    let mut borrow = 0;
+    for i in 0..a.len() {
+        let mut d = a[i] as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if d < 0 {
+            d += BASE as i64;
+            borrow = 1;
+        }
+        v.push(d as u32);
+    }
+    while v.last() == Some(&0) {
+        v.pop();
+    }
+    v
+}
+
+fn mul_digits(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut v = vec![0u64; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate() {
+        let mut carry = 0;
+        for (j, y) in b.iter().enumerate() {
+            let p = v[i + j] + *x as u64 * *y as u64 + carry;
+            v[i + j] = p % BASE;
+            carry = p / BASE;
+        }
+        v[i + b.len()] += carry;
+    }
+    let mut v: Vec<u32> = v.into_iter().map(|d| d as u32).collect();
+    while v.last() == Some(&0) {
+        v.pop();
+    }
+    v
+}
+
+// Divides by a single limb, returns quotient and remainder.
+fn div_rem_small(a: &[u32], b: u32) -> (Vec<u32>, u32) {
+    let mut q = vec![0; a.len()];
+    let mut r: u64 = 0;
+    for i in (0..a.len()).rev() {
+        let x = r * BASE + a[i] as u64;
+        q[i] = (x / b as u64) as u32;
+        r = x % b as u64;
+    }
+    while q.last() == Some(&0) {
+        q.pop();
+    }
+    (q, r as u32)
+}
+
+// Multiplies by a single limb keeping an extra most significant limb, even
+// if it is zero.
+fn scale(a: &[u32], f: u32) -> Vec<u32> {
+    let mut v = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0;
+    for d in a {
+        let p = *d as u64 * f as u64 + carry;
+        v.push((p % BASE) as u32);
+        carry = p / BASE;
+    }
+    v.push(carry as u32);
+    v
+}
+
+// Schoolbook long division (Knuth's algorithm D). Both operands are scaled
+// so that the leading limb of the divisor is at least half the base, then
+// every limb of the quotient is estimated from the leading limbs and
+// corrected at most twice.
+fn div_rem(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if cmp_digits(a, b) == Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    if b.len() == 1 {
+        let (q, r) = div_rem_small(a, b[0]);
+        return (q, if r == 0 { Vec::new() } else { vec![r] });
+    }
+
+    let n = b.len();
+    let f = (BASE / (b[n - 1] as u64 + 1)) as u32;
+    let mut u = scale(a, f);
+    let mut v = scale(b, f);
+    v.pop();
+    let (v1, v2) = (v[n - 1] as u64, v[n - 2] as u64);
+
+    let mut q = vec![0; a.len() - n + 1];
+    for j in (0..a.len() - n + 1).rev() {
+        let x = u[j + n] as u64 * BASE + u[j + n - 1] as u64;
+        let (mut qhat, mut rhat) = (x / v1, x % v1);
+        while qhat >= BASE || qhat * v2 > rhat * BASE + u[j + n - 2] as u64 {
+            qhat -= 1;
+            rhat += v1;
+            if rhat >= BASE {
+                break;
+            }
+        }
+
+        // subtract qhat * v from the current limbs
+        let (mut carry, mut borrow) = (0, 0);
+        for i in 0..n {
+            let p = qhat * v[i] as u64 + carry;
+            carry = p / BASE;
+            let t = u[i + j] as i64 - (p % BASE) as i64 - borrow;
+            borrow = if t < 0 { 1 } else { 0 };
+            u[i + j] = (t + borrow * BASE as i64) as u32;
+        }
+        let t = u[j + n] as i64 - carry as i64 - borrow;
+        if t < 0 {
+            // qhat was one too large, add v back
+            u[j + n] = (t + BASE as i64) as u32;
+            qhat -= 1;
+            let mut carry = 0;
+            for i in 0..n {
+                let s = u[i + j] as u64 + v[i] as u64 + carry;
+                u[i + j] = (s % BASE) as u32;
+                carry = s / BASE;
+            }
+            u[j + n] = ((u[j + n] as u64 + carry) % BASE) as u32;
+        } else {
+            u[j + n] = t as u32;
+        }
+        q[j] = qhat as u32;
+    }
+
+    while q.last() == Some(&0) {
+        q.pop();
+    }
+    u.truncate(n);
+    (q, div_rem_small(&u, f).0)
+}
+
+impl<'a, 'b> Add<&'b BigInt> for &'a BigInt {
+    type Output = BigInt;
+    fn add(self, other: &BigInt) -> BigInt {
+        if self.negative == other.negative {
+            return BigInt::new(self.negative, add_digits(&self.digits, &other.digits));
+        }
+        match cmp_digits(&self.digits, &other.digits) {
+            Ordering::Less => BigInt::new(other.negative, sub_digits(&other.digits, &self.digits)),
+            _ => BigInt::new(self.negative, sub_digits(&self.digits, &other.digits)),
+        }
+    }
+}
+
+impl<'a, 'b> Sub<&'b BigInt> for &'a BigInt {
+    type Output = BigInt;
+    fn sub(self, other: &BigInt) -> BigInt {
+        self + &-other
+    }
+}
+
+impl<'a, 'b> Mul<&'b BigInt> for &'a BigInt {
+    type Output = BigInt;
+    fn mul(self, other: &BigInt) -> BigInt {
+        BigInt::new(self.negative != other.negative, mul_digits(&self.digits, &other.digits))
+    }
+}
+
+impl<'a> Neg for &'a BigInt {
+    type Output = BigInt;
+    fn neg(self) -> BigInt {
+        BigInt::new(!self.negative, self.digits.clone())
+    }
+}
+
+impl Ord for BigInt {
+    fn cmp(&self, other: &BigInt) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => cmp_digits(&self.digits, &other.digits),
+            (true, true) => cmp_digits(&other.digits, &self.digits),
+        }
+    }
+}
+
+impl PartialOrd for BigInt {
+    fn partial_cmp(&self, other: &BigInt) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromStr for BigInt {
+    type Err = ();
+    fn from_str(s: &str) -> Result<BigInt, ()> {
+        let (negative, s) = if s.starts_with('-') { (true, &s[1..]) } else { (false, s) };
+        if s.is_empty() || !s.bytes().all(|c| c.is_ascii_digit()) {
+            return Err(());
+        }
+
+        let mut digits = Vec::new();
+        let mut end = s.len();
+        while end > 0 {
+            let start = if end > 9 { end - 9 } else { 0 };
+            digits.push(s[start..end].parse::<u32>().unwrap());
+            end = start;
+        }
+        Ok(BigInt::new(negative, digits))
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.negative {
+            try!{ write!(f, "-") };
+        }
+        match self.digits.last() {
+            Some(d) => try!{ write!(f, "{}", d) },
+            None => return write!(f, "0"),
+        }
+        for d in self.digits.iter().rev().skip(1) {
+            try!{ write!(f, "{:09}", d) };
+        }
+        Ok(())
+    }
+}
+
+/// A number represented by a numeric leaf like `42`, `-3/4` or `1.5`.
+///
+/// Integers and rationals are exact, rationals are always reduced and have
+/// a positive denominator greater than one. Decimals are floating point
+/// numbers and make the result of any operation involving them inexact.
+#[derive(Clone, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
+pub enum Number {
+    /// An exact integer
+    Integer(BigInt),
+    /// An exact fraction of numerator and denominator
+    Rational(BigInt, BigInt),
+    /// An inexact decimal number
+    Real(f64),
+}
+
+#[unstable(feature = "ers1")]
+impl Number {
+    /// Returns the reduced fraction `n/d` or `None` if `d` is zero.
+    #[unstable(feature = "ers1")]
+    pub fn rational(n: BigInt, d: BigInt) -> Option<Number> {
+        if d.is_zero() {
+            return None;
+        }
+        let g = n.gcd(&d);
+        Number::reduced(n.div_rem(&g).unwrap().0, d.div_rem(&g).unwrap().0)
+    }
+
+    // Same as `rational` for a fraction without common divisors, only the
+    // sign is normalized.
+    fn reduced(n: BigInt, d: BigInt) -> Option<Number> {
+        if d.is_zero() {
+            return None;
+        }
+        let (n, d) = if d.is_negative() { (-&n, -&d) } else { (n, d) };
+        if d == BigInt::from_i64(1) {
+            Some(Number::Integer(n))
+        } else {
+            Some(Number::Rational(n, d))
+        }
+    }
+
+    /// Returns the number represented by the expression if it is a numeric
+    /// leaf.
+    #[unstable(feature = "ers1")]
+    pub fn from_expression(e: &Expression) -> Option<Number> {
+        match *e {
+            Expression::Integer(n) => Some(Number::Integer(BigInt::from_i64(n))),
+            Expression::BigInteger(ref n) => Some(Number::Integer(n.clone())),
+            Expression::Rational(ref n, ref d) => Some(Number::Rational(n.clone(), d.clone())),
+            Expression::Float(x) => Some(Number::Real(x)),
+            _ => None
+        }
+    }
+
+    /// Converts the number into a numeric leaf.
+    #[unstable(feature = "ers1")]
+    pub fn to_expression(&self) -> Expression {
+        match *self {
+            Number::Integer(ref n) => {
+                match n.to_i64() {
+                    Some(n) => Expression::Integer(n),
+                    None => Expression::BigInteger(n.clone())
+                }
+            }
+            Number::Rational(ref n, ref d) => Expression::Rational(n.clone(), d.clone()),
+            Number::Real(x) => Expression::Float(x),
+        }
+    }
+
+    /// Returns true if the number is exactly zero.
+    #[unstable(feature = "ers1")]
+    pub fn is_zero(&self) -> bool {
+        match *self {
+            Number::Integer(ref n) => n.is_zero(),
+            _ => false
+        }
+    }
+
+    /// Returns true if the number is exactly one.
+    #[unstable(feature = "ers1")]
+    pub fn is_one(&self) -> bool {
+        match *self {
+            Number::Integer(ref n) => *n == BigInt::from_i64(1),
+            _ => false
+        }
+    }
+
+    /// Returns the nearest floating point number.
+    #[unstable(feature = "ers1")]
+    pub fn to_f64(&self) -> f64 {
+        match *self {
+            Number::Integer(ref n) => n.to_f64(),
+            Number::Rational(ref n, ref d) => n.to_f64() / d.to_f64(),
+            Number::Real(x) => x,
+        }
+    }
+
+    /// Divides by `other` or returns `None` if it is exactly zero.
+    #[unstable(feature = "ers1")]
+    pub fn checked_div(&self, other: &Number) -> Option<Number> {
+        match (self.fraction(), other.fraction()) {
+            (Some((a, b)), Some((c, d))) => Number::rational(&a * &d, &b * &c),
+            _ => Some(Number::Real(self.to_f64() / other.to_f64())),
+        }
+    }
+
+    /// Raises the number to the power of `exp`. The result is exact for
+    /// exact bases and integer exponents. Returns `None` if the result is
+    /// undefined like for `0^-1`, not real like for `(-1)^0.5` or if an
+    /// exact result would have more than 10000 digits.
+    #[unstable(feature = "ers1")]
+    pub fn pow(&self, exp: &Number) -> Option<Number> {
+        if let (Some((n, d)), &Number::Integer(ref e)) = (self.fraction(), exp) {
+            let k = match e.to_i64() {
+                Some(k) if k.abs() <= u32::max_value() as i64 => k,
+                _ => return None
+            };
+            if n.log10().max(d.log10()) * k.abs() as f64 > MAX_POWER_DIGITS {
+                return None;
+            }
+            // powers of a reduced fraction are reduced
+            let (n, d) = (n.pow(k.abs() as u32), d.pow(k.abs() as u32));
+            return if k < 0 { Number::reduced(d, n) } else { Number::reduced(n, d) };
+        }
+
+        let x = self.to_f64().powf(exp.to_f64());
+        if x.is_nan() { None } else { Some(Number::Real(x)) }
+    }
+
+    // numerator and denominator of exact numbers
+    fn fraction(&self) -> Option<(BigInt, BigInt)> {
+        match *self {
+            Number::Integer(ref n) => Some((n.clone(), BigInt::from_i64(1))),
+            Number::Rational(ref n, ref d) => Some((n.clone(), d.clone())),
+            Number::Real(_) => None,
+        }
+    }
+}
+
+impl<'a, 'b> Add<&'b Number> for &'a Number {
+    type Output = Number;
+    fn add(self, other: &Number) -> Number {
+        match (self, other) {
+            (&Number::Integer(ref a), &Number::Integer(ref b)) => Number::Integer(a + b),
+            _ => match (self.fraction(), other.fraction()) {
+                (Some((a, b)), Some((c, d))) => Number::rational(&(&a * &d) + &(&c * &b), &b * &d).unwrap(),
+                _ => Number::Real(self.to_f64() + other.to_f64()),
+            }
+        }
+    }
+}
+
+impl<'a, 'b> Sub<&'b Number> for &'a Number {
+    type Output = Number;
+    fn sub(self, other: &Number) -> Number {
+        self + &-other
+    }
+}
+
+impl<'a, 'b> Mul<&'b Number> for &'a Number {
+    type Output = Number;
+    fn mul(self, other: &Number) -> Number {
+        match (self, other) {
+            (&Number::Integer(ref a), &Number::Integer(ref b)) => Number::Integer(a * b),
+            _ => match (self.fraction(), other.fraction()) {
+                (Some((a, b)), Some((c, d))) => Number::rational(&a * &c, &b * &d).unwrap(),
+                _ => Number::Real(self.to_f64() * other.to_f64()),
+            }
+        }
+    }
+}
+
+impl<'a> Neg for &'a Number {
+    type Output = Number;
+    fn neg(self) -> Number {
+        match *self {
+            Number::Integer(ref n) => Number::Integer(-n),
+            Number::Rational(ref n, ref d) => Number::Rational(-n, d.clone()),
+            Number::Real(x) => Number::Real(-x),
+        }
+    }
+}
+
+impl PartialOrd for Number {
+    fn partial_cmp(&self, other: &Number) -> Option<Ordering> {
+        match (self.fraction(), other.fraction()) {
+            (Some((a, b)), Some((c, d))) => Some((&a * &d).cmp(&(&c * &b))),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+}
+
+impl FromStr for Number {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Number, ()> {
+        if let Ok(n) = s.parse::<BigInt>() {
+            return Ok(Number::Integer(n));
+        }
+
+        if let Some(i) = s.find('/') {
+            let n = try!{ s[..i].parse::<BigInt>() };
+            let d = try!{ s[i + 1..].parse::<BigInt>() };
+            // only reduced fractions are numbers so that printing a number
+            // gives back the atom
+            return match Number::rational(n, d) {
+                Some(r @ Number::Rational(..)) => {
+                    if r.to_string() == s { Ok(r) } else { Err(()) }
+                }
+                _ => Err(())
+            };
+        }
+
+        // decimals need a point so that atoms like `inf` stay symbols
+        let mut parts = s.trim_start_matches('-').splitn(2, 'e');
+        let mantissa = parts.next().unwrap();
+        let exponent = parts.next().map_or("0", |e| e.trim_start_matches('-'));
+        if mantissa.contains('.') && mantissa.bytes().all(|c| c.is_ascii_digit() || c == b'.')
+            && exponent.len() > 0 && exponent.bytes().all(|c| c.is_ascii_digit()) {
+            if let Ok(x) = s.parse::<f64>() {
+                return Ok(Number::Real(x));
+            }
+        }
+        Err(())
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Number::Integer(ref n) => write!(f, "{}", n),
+            Number::Rational(ref n, ref d) => write!(f, "{}/{}", n, d),
+            Number::Real(x) => {
+                let s = format!("{:?}", x);
+                match s.find('e') {
+                    // large and small numbers are printed like `1e21`
+                    Some(i) if !s.contains('.') => write!(f, "{}.0{}", &s[..i], &s[i..]),
+                    _ => write!(f, "{}", s)
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BigInt, Number};
+
+    fn big(s: &str) -> BigInt {
+        s.parse::<BigInt>().unwrap()
+    }
+
+    fn num(s: &str) -> Number {
+        s.parse::<Number>().unwrap()
+    }
+
+    #[test]
+    fn big_int() {
+        let a = big("123456789012345678901234567890");
+        let b = big("-987654321098765432109876543210");
+
+        assert_eq!((&a + &b).to_string(), "-864197532086419753208641975320");
+        assert_eq!((&a - &b).to_string(), "1111111110111111111011111111100");
+        assert_eq!((&a * &b).to_string(),
+                   "-121932631137021795226185032733622923332237463801111263526900");
+
+        let (q, r) = b.div_rem(&a).unwrap();
+        assert_eq!(q.to_string(), "-8");
+        assert_eq!(r.to_string(), "-9000000000900000000090");
+        assert_eq!(a.gcd(&b).to_string(), "9000000000900000000090");
+
+        assert_eq!(big("2").pow(100).to_string(), "1267650600228229401496703205376");
+        assert_eq!(big("-0").to_string(), "0");
+        assert_eq!(big("1000000000").to_string(), "1000000000");
+        assert!("1a".parse::<BigInt>().is_err());
+    }
+
+    #[test]
+    fn big_int_division() {
+        // pseudo random operands of various lengths including limbs close
+        // to the base which need the quotient estimate to be corrected
+        let mut seed: u64 = 12345;
+        let mut next = || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            match (seed >> 33) % 4 {
+                0 => 999_999_999,
+                1 => 0,
+                _ => ((seed >> 20) % 1_000_000_000) as u32
+            }
+        };
+        for &(m, n) in [(1, 1), (3, 2), (5, 2), (8, 3), (12, 7), (20, 19), (30, 10)].iter() {
+            for _ in 0..20 {
+                let a = BigInt::new(false, (0..m).map(|_| next()).collect());
+                let mut digits: Vec<u32> = (0..n).map(|_| next()).collect();
+                digits.push(next().max(1));
+                let b = BigInt::new(false, digits);
+                let (q, r) = a.div_rem(&b).unwrap();
+                assert_eq!(&(&q * &b) + &r, a);
+                assert!(r < b && !r.is_negative());
+            }
+        }
+
+        let a = big("2").pow(3000);
+        let b = big("3").pow(2000);
+        assert_eq!(a.gcd(&b).to_string(), "1");
+        assert_eq!((&a * &b).gcd(&(&b * &big("7"))), b);
+        assert_eq!(big("-12").gcd(&big("18")).to_string(), "6");
+        assert_eq!(big("0").gcd(&big("0")).to_string(), "0");
+    }
+
+    #[test]
+    fn large_powers() {
+        // close to the digit limit but still fast
+        let n = num("2/3").pow(&num("20000")).unwrap();
+        match n {
+            Number::Rational(ref n, ref d) => {
+                assert_eq!(n.to_string().len(), 6021);
+                assert_eq!(d.to_string().len(), 9543);
+            }
+            _ => panic!("not a rational")
+        }
+        assert_eq!(num("-2/3").pow(&num("-3")).unwrap().to_string(), "-27/8");
+        assert!(num("0").pow(&num("-2")).is_none());
+    }
+
+    #[test]
+    fn number() {
+        assert_eq!((&num("1/2") + &num("1/3")).to_string(), "5/6");
+        assert_eq!((&num("1/2") + &num("1/2")).to_string(), "1");
+        assert_eq!((&num("1/2") * &num("0.5")).to_string(), "0.25");
+        assert_eq!(num("2").checked_div(&num("-4")).unwrap().to_string(), "-1/2");
+        assert!(num("2").checked_div(&num("0")).is_none());
+        assert_eq!(num("2/3").pow(&num("-2")).unwrap().to_string(), "9/4");
+        assert_eq!(num("4").pow(&num("0.5")).unwrap().to_string(), "2.0");
+        assert!(num("-1").pow(&num("0.5")).is_none());
+        assert!(num("1/3") < num("0.5"));
+
+        assert!("2/4".parse::<Number>().is_err());
+        assert!("1/0".parse::<Number>().is_err());
+        assert!("inf".parse::<Number>().is_err());
+        assert!("1e5".parse::<Number>().is_err());
+        assert_eq!(num("1.5e-7").to_string(), "1.5e-7");
+        assert_eq!(num("1.0e21").to_string(), "1.0e21");
+        assert!("x".parse::<Number>().is_err());
+    }
+}
@@ -0,0 +1,492 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use std::str::FromStr;
+
+use arithmetic;
+use binding::{Bind, Binding};
+use context::Context;
+use expression::{Expression, ParserError, Position, excerpt, parse_with_starts};
+use matching::Match;
+
+/// A `Rule` rewrites expressions matching the left hand side pattern to the
+/// right hand side template bound with the bindings of the match.
+///
+/// Its S-Expression representation is `(Rule lhs rhs)`.
+///
+/// # Example
+///
+/// ```
+/// use ers::{Expression, Rule};
+///
+/// let rule = "(Rule (Plus x_ x_) (Times 2 x))".parse::<Rule>().unwrap();
+/// let expr = "(Plus a a)".parse::<Expression>().unwrap();
+///
+/// let res = rule.apply(&expr).unwrap();
+///
+/// assert_eq!(format!("{:?}", res), "(Times 2 a)");
+/// ```
+#[derive(Clone, Debug)]
+#[unstable(feature = "ers1")]
+pub struct Rule {
+    /// The pattern an expression has to match
+    pub lhs: Expression,
+    /// The right hand side which replaces the expression
+    pub rhs: Rhs,
+}
+
+/// The right hand side of a `Rule`.
+#[derive(Clone)]
+#[unstable(feature = "ers1")]
+pub enum Rhs {
+    /// A template which is bound with the bindings of the match
+    Template(Expression),
+    /// A function computing the replacement from the bindings of the match.
+    /// Returning `None` means that the rule does not apply.
+    Native(Arc<Fn(&HashMap<String, Binding>) -> Option<Expression> + Send + Sync>),
+}
+
+impl fmt::Debug for Rhs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Rhs::Template(ref e) => write!(f, "{:?}", e),
+            Rhs::Native(_) => write!(f, "<native>"),
+        }
+    }
+}
+
+#[unstable(feature = "ers1")]
+impl Rule {
+    /// Creates a rule rewriting `lhs` to `rhs`.
+    #[unstable(feature = "ers1")]
+    pub fn new(lhs: Expression, rhs: Expression) -> Rule {
+        Rule { lhs: lhs, rhs: Rhs::Template(rhs) }
+    }
+
+    /// Creates a rule whose right hand side is computed by `f` from the
+    /// bindings. If `f` returns `None` for a match the remaining matches of
+    /// `lhs` are tried before the rule is considered not to apply.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ers::{Expression, Rule, Binding};
+    ///
+    /// let add = Rule::native("(Plus x_ y_)".parse::<Expression>().unwrap(), |bs| {
+    ///     let num = |b: &Binding| match b.as_expression() {
+    ///         Some(&Expression::Integer(n)) => Some(n),
+    ///         _ => None
+    ///     };
+    ///     match (num(&bs["x"]), num(&bs["y"])) {
+    ///         (Some(x), Some(y)) => Some(Expression::Integer(x + y)),
+    ///         _ => None
+    ///     }
+    /// });
+    ///
+    /// let expr = "(Plus 1 2)".parse::<Expression>().unwrap();
+    /// assert_eq!(format!("{:?}", add.apply(&expr).unwrap()), "3");
+    ///
+    /// let expr = "(Plus 1 a)".parse::<Expression>().unwrap();
+    /// assert!(add.apply(&expr).is_none());
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn native<F>(lhs: Expression, f: F) -> Rule
+        where F: Fn(&HashMap<String, Binding>) -> Option<Expression> + Send + Sync + 'static {
+        Rule { lhs: lhs, rhs: Rhs::Native(Arc::new(f)) }
+    }
+
+    /// Converts an expression of the form `(Rule lhs rhs)` into a rule.
+    #[unstable(feature = "ers1")]
+    pub fn from_expression(e: &Expression) -> Option<Rule> {
+        match *e {
+            Expression::List(ref es) if es.len() == 3 => {
+                match es[0] {
+                    Expression::Atom(ref s) if s == "Rule" => {
+                        Some(Rule::new(es[1].clone(), es[2].clone()))
+                    }
+                    _ => None
+                }
+            }
+            _ => None
+        }
+    }
+
+    /// Returns the rewritten expression if the expression matches the left
+    /// hand side and `None` otherwise. Subexpressions are not considered.
+    #[unstable(feature = "ers1")]
+    pub fn apply(&self, e: &Expression) -> Option<Expression> {
+        self.apply_bindings(e, None).map(|(res, _)| res)
+    }
+
+    /// Same as `apply` but matches in the given context.
+    #[unstable(feature = "ers1")]
+    pub fn apply_in(&self, e: &Expression, ctx: &Context) -> Option<Expression> {
+        self.apply_bindings(e, Some(ctx)).map(|(res, _)| res)
+    }
+
+    // Same as `apply_in` but returns the bindings of the match as well.
+    // Without a context the rule is matched like in an empty one.
+    fn apply_bindings<'a>(&self, e: &'a Expression, ctx: Option<&Context>)
+                          -> Option<(Expression, HashMap<String, Binding<'a>>)> {
+        let mut matches = match ctx {
+            Some(ctx) => e.match_all_in(&self.lhs, ctx),
+            None => e.match_all(&self.lhs)
+        };
+        match self.rhs {
+            Rhs::Template(ref t) => matches.next().map(|bs| (t.clone().bind(&bs), bs)),
+            Rhs::Native(ref f) => matches.filter_map(|bs| f(&bs).map(|res| (res, bs))).next(),
+        }
+    }
+}
+
+impl FromStr for Rule {
+    type Err = RuleError;
+    fn from_str(s: &str) -> Result<Rule, RuleError> {
+        let (e, start, _) = try!{ parse_with_starts(s) };
+
+        match Rule::from_expression(&e) {
+            Some(r) => Ok(r),
+            None => Err(RuleError::InvalidRule(start))
+        }
+    }
+}
+
+/// The error returned if parsing a `Rule` or `RuleSet` fails.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
+pub enum RuleError {
+    /// The input is not a well formed expression
+    ParserError(ParserError),
+    /// The expression at the given position is not of the form
+    /// `(Rule lhs rhs)`
+    InvalidRule(Position),
+}
+
+#[unstable(feature = "ers1")]
+impl RuleError {
+    /// Returns the error message followed by the line of the source the
+    /// error occurred in, like `ParserError::message`.
+    #[unstable(feature = "ers1")]
+    pub fn message(&self, source: &str) -> String {
+        match *self {
+            RuleError::ParserError(ref err) => err.message(source),
+            RuleError::InvalidRule(pos) => excerpt(self, source, pos),
+        }
+    }
+}
+
+impl From<ParserError> for RuleError {
+    fn from(err: ParserError) -> RuleError {
+        RuleError::ParserError(err)
+    }
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RuleError::ParserError(ref err) => write!(f, "{}", err),
+            RuleError::InvalidRule(pos) => {
+                write!(f, "expected a rule of the form (Rule lhs rhs) at line {}, column {}",
+                       pos.line, pos.column)
+            }
+        }
+    }
+}
+
+impl Error for RuleError {
+    fn source(&self) -> Option<&(Error + 'static)> {
+        match *self {
+            RuleError::ParserError(ref err) => Some(err),
+            RuleError::InvalidRule(_) => None,
+        }
+    }
+}
+
+/// A `RuleSet` is an ordered list of rules together with the `Context` in
+/// which they are matched. Whenever an expression is rewritten with a rule
+/// set the rules are tried in order and the first matching rule is applied.
+///
+/// Its S-Expression representation is a list of rules like
+/// `((Rule a b) (Rule b c))`. A single `(Rule lhs rhs)` is accepted as
+/// well.
+///
+/// # Example
+///
+/// ```
+/// use ers::{Expression, RuleSet};
+///
+/// let rules = "((Rule (Sin 0) 0) (Rule (Cos 0) 1))".parse::<RuleSet>().unwrap();
+/// let expr = "(Plus (Sin 0) (Cos 0))".parse::<Expression>().unwrap();
+///
+/// let res = expr.replace_all_rules(&rules);
+///
+/// assert_eq!(format!("{:?}", res), "(Plus 0 1)");
+/// ```
+#[unstable(feature = "ers1")]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    context: Context,
+}
+
+#[unstable(feature = "ers1")]
+impl RuleSet {
+    /// Creates an empty rule set with an empty context.
+    #[unstable(feature = "ers1")]
+    pub fn new() -> RuleSet {
+        RuleSet {
+            rules: Vec::new(),
+            context: Context::new(),
+        }
+    }
+
+    /// Appends a rule. It has a lower priority than all existing rules.
+    #[unstable(feature = "ers1")]
+    pub fn push(&mut self, rule: Rule) {
+        self.rules.push(rule);
+    }
+
+    /// Appends the built-in rules evaluating arithmetic on numeric atoms,
+    /// i.e. integers like `-12`, rationals like `3/4` and decimals like
+    /// `1.5`. Integers and rationals are exact and of arbitrary size.
+    ///
+    /// `Plus` and `Times` combine all numeric arguments, `Minus`,
+    /// `Subtract`, `Divide` and `Power` evaluate if their arguments are
+    /// numeric. The comparisons `Less`, `LessEqual`, `Greater`,
+    /// `GreaterEqual`, `Equal` and `Unequal` evaluate to `True` or `False`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use ers::{Expression, RuleSet};
+    ///
+    /// let mut rules = "(Rule (Square x_) (Times x x))".parse::<RuleSet>().unwrap();
+    /// rules.push_arithmetic();
+    ///
+    /// let expr = "(Plus (Square 1/2) a 1)".parse::<Expression>().unwrap();
+    /// let res = expr.replace_repeated_rules(&rules, 100).unwrap();
+    ///
+    /// assert_eq!(format!("{:?}", res), "(Plus 5/4 a)");
+    /// ```
+    #[unstable(feature = "ers1")]
+    pub fn push_arithmetic(&mut self) {
+        for r in arithmetic::rules() {
+            self.push(r);
+        }
+    }
+
+    /// Returns the rules in order of their priority.
+    #[unstable(feature = "ers1")]
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Returns the context the rules are matched in.
+    #[unstable(feature = "ers1")]
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Returns the context mutably, e.g. to register pattern tests.
+    #[unstable(feature = "ers1")]
+    pub fn context_mut(&mut self) -> &mut Context {
+        &mut self.context
+    }
+
+    /// Converts a list of `(Rule lhs rhs)` expressions or a single one into
+    /// a rule set. On failure the index of the first element of the list
+    /// which is not a rule is returned, or `None` if the expression is
+    /// neither a list of rules nor a rule.
+    #[unstable(feature = "ers1")]
+    pub fn from_expression(e: &Expression) -> Result<RuleSet, Option<usize>> {
+        let mut rules = RuleSet::new();
+
+        if let Some(r) = Rule::from_expression(e) {
+            rules.push(r);
+            return Ok(rules);
+        }
+
+        match *e {
+            Expression::List(ref es) if !is_rule_head(es.first()) => {
+                for (i, e) in es.iter().enumerate() {
+                    match Rule::from_expression(e) {
+                        Some(r) => rules.push(r),
+                        None => { return Err(Some(i)); }
+                    }
+                }
+                Ok(rules)
+            }
+            _ => Err(None)
+        }
+    }
+
+    /// Applies the first rule matching the expression itself and returns
+    /// `None` if no rule matches.
+    #[unstable(feature = "ers1")]
+    pub fn apply(&self, e: &Expression) -> Option<Expression> {
+        for r in self.rules.iter() {
+            if let Some(res) = r.apply_in(e, &self.context) {
+                return Some(res);
+            }
+        }
+        None
+    }
+}
+
+// Same as `RuleSet::apply` but returns the index of the applied rule and
+// the bindings of the match as well.
+pub fn apply_traced<'a>(rules: &RuleSet, e: &'a Expression)
+                        -> Option<(usize, Expression, HashMap<String, Binding<'a>>)> {
+    for (i, r) in rules.rules.iter().enumerate() {
+        if let Some((res, bs)) = r.apply_bindings(e, Some(&rules.context)) {
+            return Some((i, res, bs));
+        }
+    }
+    None
+}
+
+impl From<Vec<Rule>> for RuleSet {
+    fn from(rules: Vec<Rule>) -> RuleSet {
+        RuleSet {
+            rules: rules,
+            context: Context::new(),
+        }
+    }
+}
+
+// A malformed rule like `(Rule a)` is reported as a whole rather than at
+// its elements.
+fn is_rule_head(e: Option<&Expression>) -> bool {
+    match e {
+        Some(&Expression::Atom(ref s)) => s == "Rule",
+        _ => false
+    }
+}
+
+impl FromStr for RuleSet {
+    type Err = RuleError;
+    fn from_str(s: &str) -> Result<RuleSet, RuleError> {
+        let (e, start, starts) = try!{ parse_with_starts(s) };
+
+        RuleSet::from_expression(&e).map_err(|i| {
+            // the position of the offending element or the whole expression
+            RuleError::InvalidRule(i.map_or(start, |i| starts[i]))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use binding::Binding;
+    use context::Attribute;
+    use expression::{Expression, ParserError, ErrorCode};
+    use super::{Rule, RuleError, RuleSet};
+
+    fn number(b: &Binding) -> Option<i64> {
+        match b.as_expression() {
+            Some(&Expression::Integer(n)) => Some(n),
+            _ => None
+        }
+    }
+
+    #[test]
+    fn native() {
+        let mut rules = RuleSet::new();
+        rules.context_mut().set_attribute("Plus", Attribute::Orderless);
+        rules.push(Rule::native("(Plus x_ y_ z___)".parse::<Expression>().unwrap(), |bs| {
+            match (number(&bs["x"]), number(&bs["y"])) {
+                (Some(x), Some(y)) => {
+                    let mut v = vec![
+                        Expression::Atom("Plus".to_string()),
+                        Expression::Integer(x + y),
+                    ];
+                    v.extend(bs["z"].as_sequence().unwrap().iter().cloned());
+                    Some(Expression::List(v))
+                }
+                _ => None
+            }
+        }));
+        rules.push("(Rule (Plus x_) x)".parse::<Rule>().unwrap());
+
+        let expr = "(f (Plus 1 a 2 3))".parse::<Expression>().unwrap();
+        let res = expr.replace_repeated_rules(&rules, 10).unwrap();
+        assert_eq!(format!("{:?}", res), "(f (Plus 6 a))");
+
+        let expr = "(Plus a b)".parse::<Expression>().unwrap();
+        assert!(rules.apply(&expr).is_none());
+    }
+
+    #[test]
+    fn invalid_rule_position() {
+        let position = |s: &str| match s.parse::<RuleSet>() {
+            Err(RuleError::InvalidRule(pos)) => (pos.line, pos.column),
+            res => panic!("unexpected error {:?}", res.err())
+        };
+
+        // the first element which is not a rule
+        assert_eq!(position("((Rule a b)\n #| c |# (Rule b) (Rule c d))"), (2, 10));
+        assert_eq!(position("((Rule a b) c)"), (1, 13));
+        // the whole expression otherwise
+        assert_eq!(position("  ; rules\n  (Rule a)"), (2, 3));
+        assert_eq!(position("  a"), (1, 3));
+
+        let e = "((Rule a b) (f))".parse::<Expression>().unwrap();
+        assert_eq!(RuleSet::from_expression(&e).err(), Some(Some(1)));
+
+        match "\n #| |# a".parse::<Rule>() {
+            Err(RuleError::InvalidRule(pos)) => {
+                assert_eq!((pos.offset, pos.line, pos.column), (8, 2, 8));
+            }
+            res => panic!("unexpected error {:?}", res.err())
+        }
+    }
+
+    #[test]
+    fn rule_error() {
+        // malformed expressions are reported by the parser
+        match "((Rule a b)".parse::<RuleSet>() {
+            Err(RuleError::ParserError(ParserError::SyntaxError(code, _))) => {
+                assert_eq!(code, ErrorCode::UnbalancedParens);
+            }
+            res => panic!("unexpected error {:?}", res.err())
+        }
+
+        let source = "((Rule a b)\n (Rule b))";
+        let err = source.parse::<RuleSet>().err().unwrap();
+        assert_eq!(err.message(source), "\
+expected a rule of the form (Rule lhs rhs) at line 2, column 2
+  |
+2 |  (Rule b))
+  |  ^");
+    }
+
+    #[test]
+    fn native_send() {
+        let rule = Rule::native("(Minus x_)".parse::<Expression>().unwrap(), |bs| {
+            number(&bs["x"]).map(|x| Expression::Integer(-x))
+        });
+
+        // native rules can be shared with other threads
+        let res = thread::spawn(move || {
+            rule.apply(&"(Minus 2)".parse::<Expression>().unwrap())
+        }).join().unwrap();
+        assert_eq!(res, Some(Expression::Integer(-2)));
+    }
+}
@@ -0,0 +1,392 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Composable rewriting strategies.
+//!
+//! A [`Strategy`](trait.Strategy.html) transforms an expression or fails.
+//! Rules are the basic strategies, they are combined with sequential
+//! composition, choice and the traversal combinators `all`, `one` and `some`
+//! which apply a strategy to the arguments of a list.
+//!
+//! ```
+//! use ers::Expression;
+//! use ers::strategy::{Strategy, choice, try_, bottomup};
+//!
+//! let zero = "(Rule (Plus x_ 0) x)".parse::<ers::Rule>().unwrap();
+//! let one = "(Rule (Times x_ 1) x)".parse::<ers::Rule>().unwrap();
+//!
+//! let simplify = bottomup(try_(choice(zero, one)));
+//!
+//! let expr = "(Plus (Times y 1) 0)".parse::<Expression>().unwrap();
+//! assert_eq!(format!("{:?}", simplify.apply(&expr).unwrap()), "y");
+//! ```
+
+use expression::{self, Expression};
+use rule::{Rule, RuleSet};
+
+/// A `Strategy` rewrites an expression and returns `None` if it fails.
+#[unstable(feature = "ers1")]
+pub trait Strategy {
+    /// Applies the strategy to the expression.
+    fn apply(&self, e: &Expression) -> Option<Expression>;
+}
+
+#[unstable(feature = "ers1")]
+impl<F> Strategy for F where F: Fn(&Expression) -> Option<Expression> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        self(e)
+    }
+}
+
+#[unstable(feature = "ers1")]
+impl Strategy for Rule {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        Rule::apply(self, e)
+    }
+}
+
+#[unstable(feature = "ers1")]
+impl Strategy for RuleSet {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        RuleSet::apply(self, e)
+    }
+}
+
+#[unstable(feature = "ers1")]
+impl<'a> Strategy for &'a RuleSet {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        RuleSet::apply(self, e)
+    }
+}
+
+#[unstable(feature = "ers1")]
+impl Strategy for Box<Strategy> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        (**self).apply(e)
+    }
+}
+
+/// The strategy returned by `id`.
+#[unstable(feature = "ers1")]
+pub struct Id;
+
+/// Always succeeds and leaves the expression unchanged.
+#[unstable(feature = "ers1")]
+pub fn id() -> Id {
+    Id
+}
+
+#[unstable(feature = "ers1")]
+impl Strategy for Id {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        Some(e.clone())
+    }
+}
+
+/// The strategy returned by `fail`.
+#[unstable(feature = "ers1")]
+pub struct Fail;
+
+/// Always fails.
+#[unstable(feature = "ers1")]
+pub fn fail() -> Fail {
+    Fail
+}
+
+#[unstable(feature = "ers1")]
+impl Strategy for Fail {
+    fn apply(&self, _: &Expression) -> Option<Expression> {
+        None
+    }
+}
+
+/// The strategy returned by `seq`.
+#[unstable(feature = "ers1")]
+pub struct Seq<A, B>(A, B);
+
+/// Applies `a` and then `b` to the result. Fails if either of them fails.
+#[unstable(feature = "ers1")]
+pub fn seq<A: Strategy, B: Strategy>(a: A, b: B) -> Seq<A, B> {
+    Seq(a, b)
+}
+
+#[unstable(feature = "ers1")]
+impl<A: Strategy, B: Strategy> Strategy for Seq<A, B> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        self.0.apply(e).and_then(|e| self.1.apply(&e))
+    }
+}
+
+/// The strategy returned by `choice`.
+#[unstable(feature = "ers1")]
+pub struct Choice<A, B>(A, B);
+
+/// Applies `a` and if it fails `b` to the original expression.
+#[unstable(feature = "ers1")]
+pub fn choice<A: Strategy, B: Strategy>(a: A, b: B) -> Choice<A, B> {
+    Choice(a, b)
+}
+
+#[unstable(feature = "ers1")]
+impl<A: Strategy, B: Strategy> Strategy for Choice<A, B> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        self.0.apply(e).or_else(|| self.1.apply(e))
+    }
+}
+
+/// The strategy returned by `try_`.
+#[unstable(feature = "ers1")]
+pub struct Try<S>(S);
+
+/// Applies `s` and leaves the expression unchanged if it fails, i.e.
+/// `choice(s, id())`. This is `try` in Stratego.
+#[unstable(feature = "ers1")]
+pub fn try_<S: Strategy>(s: S) -> Try<S> {
+    Try(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for Try<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        Some(self.0.apply(e).unwrap_or_else(|| e.clone()))
+    }
+}
+
+/// The strategy returned by `repeat`.
+#[unstable(feature = "ers1")]
+pub struct Repeat<S>(S);
+
+/// Applies `s` until it fails. Always succeeds but does not terminate if `s`
+/// never fails.
+#[unstable(feature = "ers1")]
+pub fn repeat<S: Strategy>(s: S) -> Repeat<S> {
+    Repeat(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for Repeat<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        let mut e = e.clone();
+        while let Some(res) = self.0.apply(&e) {
+            e = res;
+        }
+        Some(e)
+    }
+}
+
+/// The strategy returned by `where_`.
+#[unstable(feature = "ers1")]
+pub struct Where<S>(S);
+
+/// Succeeds with the unchanged expression if `s` succeeds. This is `where`
+/// in Stratego.
+#[unstable(feature = "ers1")]
+pub fn where_<S: Strategy>(s: S) -> Where<S> {
+    Where(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for Where<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        self.0.apply(e).map(|_| e.clone())
+    }
+}
+
+/// The strategy returned by `all`.
+#[unstable(feature = "ers1")]
+pub struct All<S>(S);
+
+/// Applies `s` to every element of a list. Fails if `s` fails for any of
+/// them. Other expressions are left unchanged.
+#[unstable(feature = "ers1")]
+pub fn all<S: Strategy>(s: S) -> All<S> {
+    All(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for All<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        apply_all(&self.0, e)
+    }
+}
+
+fn apply_all<S: Strategy>(s: &S, e: &Expression) -> Option<Expression> {
+    match e {
+        &Expression::List(ref es) => {
+            let mut v: Vec<Expression> = Vec::new();
+            for e in es.iter() {
+                match s.apply(e) {
+                    Some(res) => expression::splice(&mut v, res),
+                    None => return None
+                }
+            }
+            Some(Expression::List(v))
+        }
+        _ => Some(e.clone())
+    }
+}
+
+/// The strategy returned by `one`.
+#[unstable(feature = "ers1")]
+pub struct One<S>(S);
+
+/// Applies `s` to the first element of a list for which it succeeds. Fails
+/// if there is no such element.
+#[unstable(feature = "ers1")]
+pub fn one<S: Strategy>(s: S) -> One<S> {
+    One(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for One<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        match e {
+            &Expression::List(ref es) => {
+                for (i, e) in es.iter().enumerate() {
+                    if let Some(res) = self.0.apply(e) {
+                        let mut v = es[..i].to_vec();
+                        expression::splice(&mut v, res);
+                        v.extend(es[i + 1..].iter().cloned());
+                        return Some(Expression::List(v));
+                    }
+                }
+                None
+            }
+            _ => None
+        }
+    }
+}
+
+/// The strategy returned by `some`.
+#[unstable(feature = "ers1")]
+pub struct Some_<S>(S);
+
+/// Applies `s` to all elements of a list for which it succeeds. Fails if it
+/// does not succeed for at least one element.
+#[unstable(feature = "ers1")]
+pub fn some<S: Strategy>(s: S) -> Some_<S> {
+    Some_(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for Some_<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        match e {
+            &Expression::List(ref es) => {
+                let mut success = false;
+                let mut v: Vec<Expression> = Vec::new();
+                for e in es.iter() {
+                    match self.0.apply(e) {
+                        Some(res) => {
+                            success = true;
+                            expression::splice(&mut v, res);
+                        }
+                        None => v.push(e.clone())
+                    }
+                }
+
+                if success { Some(Expression::List(v)) } else { None }
+            }
+            _ => None
+        }
+    }
+}
+
+/// The strategy returned by `topdown`.
+#[unstable(feature = "ers1")]
+pub struct TopDown<S>(S);
+
+/// Applies `s` to the expression and then to all subexpressions of the
+/// result, i.e. `seq(s, all(topdown(s)))`.
+#[unstable(feature = "ers1")]
+pub fn topdown<S: Strategy>(s: S) -> TopDown<S> {
+    TopDown(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for TopDown<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        self.0.apply(e).and_then(|e| apply_all(self, &e))
+    }
+}
+
+/// The strategy returned by `bottomup`.
+#[unstable(feature = "ers1")]
+pub struct BottomUp<S>(S);
+
+/// Applies `s` to all subexpressions and then to the expression itself,
+/// i.e. `seq(all(bottomup(s)), s)`.
+#[unstable(feature = "ers1")]
+pub fn bottomup<S: Strategy>(s: S) -> BottomUp<S> {
+    BottomUp(s)
+}
+
+#[unstable(feature = "ers1")]
+impl<S: Strategy> Strategy for BottomUp<S> {
+    fn apply(&self, e: &Expression) -> Option<Expression> {
+        apply_all(self, e).and_then(|e| self.0.apply(&e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expression::Expression;
+    use rule::Rule;
+    use super::*;
+
+    fn rule(s: &str) -> Rule {
+        s.parse::<Rule>().unwrap()
+    }
+
+    fn run<S: Strategy>(s: S, e: &str) -> Option<String> {
+        let e = e.parse::<Expression>().unwrap();
+        s.apply(&e).map(|res| format!("{:?}", res))
+    }
+
+    #[test]
+    fn basic() {
+        let ab = rule("(Rule a b)");
+        let bc = rule("(Rule b c)");
+
+        assert_eq!(run(id(), "a"), Some("a".to_string()));
+        assert_eq!(run(fail(), "a"), None);
+        assert_eq!(run(seq(ab.clone(), bc.clone()), "a"), Some("c".to_string()));
+        assert_eq!(run(seq(ab.clone(), bc.clone()), "b"), None);
+        assert_eq!(run(choice(ab.clone(), bc.clone()), "b"), Some("c".to_string()));
+        assert_eq!(run(try_(ab.clone()), "c"), Some("c".to_string()));
+        assert_eq!(run(where_(ab.clone()), "a"), Some("a".to_string()));
+        assert_eq!(run(repeat(choice(ab, bc)), "a"), Some("c".to_string()));
+    }
+
+    #[test]
+    fn children() {
+        let ab = rule("(Rule a b)");
+
+        assert_eq!(run(all(ab.clone()), "(a a)"), Some("(b b)".to_string()));
+        assert_eq!(run(all(ab.clone()), "(a c)"), None);
+        assert_eq!(run(one(ab.clone()), "(c a a)"), Some("(c b a)".to_string()));
+        assert_eq!(run(one(ab.clone()), "(c c)"), None);
+        assert_eq!(run(some(ab.clone()), "(a c a)"), Some("(b c b)".to_string()));
+        assert_eq!(run(some(ab), "(c c)"), None);
+    }
+
+    #[test]
+    fn traversal() {
+        let s = || try_(choice(rule("(Rule (f (g x_)) (done x))"), rule("(Rule (g x_) (k x))")));
+
+        assert_eq!(run(topdown(s()), "(f (g a))"), Some("(done a)".to_string()));
+        assert_eq!(run(bottomup(s()), "(f (g a))"), Some("(f (k a))".to_string()));
+    }
+}
@@ -0,0 +1,115 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use expression::{Expression, RewriteError};
+
+/// A single rule application recorded while rewriting with
+/// `Expression::trace_with` or `Expression::trace_repeated_rules`.
+#[derive(Clone, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
+pub struct RewriteStep {
+    /// The index of the applied rule in the rule set
+    pub rule: usize,
+    /// The left hand side of the applied rule
+    pub pattern: Expression,
+    /// The position of the rewritten subexpression in the expression as it
+    /// was before this step
+    pub position: Vec<usize>,
+    /// The bindings of the match, sequences are bound to `(Sequence ...)`
+    pub bindings: BTreeMap<String, Expression>,
+    /// The subexpression before rewriting
+    pub before: Expression,
+    /// The subexpression after rewriting
+    pub after: Expression,
+}
+
+impl fmt::Display for RewriteStep {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!{ write!(f, "{} -> {} by rule {} {} at {:?}", self.before, self.after,
+                     self.rule, self.pattern, self.position) };
+        write_bindings(f, &self.bindings)
+    }
+}
+
+fn write_bindings(f: &mut fmt::Formatter, bs: &BTreeMap<String, Expression>) -> fmt::Result {
+    for (i, (name, e)) in bs.iter().enumerate() {
+        try!{ write!(f, "{} {} = {}", if i == 0 { " with" } else { "," }, name, e) };
+    }
+    Ok(())
+}
+
+/// The steps taken to rewrite an expression, created by
+/// `Expression::trace_with` and `Expression::trace_repeated_rules`.
+///
+/// It is displayed as a derivation listing the whole expression after each
+/// step together with the applied rule.
+///
+/// # Example
+/// ```
+/// use ers::{Expression, RuleSet, Traversal};
+///
+/// let expr = "(f (f a))".parse::<Expression>().unwrap();
+/// let rules = "(Rule (f x_) (g x))".parse::<RuleSet>().unwrap();
+///
+/// let derivation = expr.trace_with(&rules, Traversal::BottomUp, 10);
+///
+/// assert_eq!(format!("{}", derivation), "\
+/// (f (f a))
+///   = (f (g a))  by rule 0 (f x_) at [1] with x = a
+///   = (g (g a))  by rule 0 (f x_) at [] with x = (g a)
+/// ");
+/// ```
+#[derive(Clone, Debug)]
+#[unstable(feature = "ers1")]
+pub struct Derivation {
+    /// The expression before rewriting
+    pub start: Expression,
+    /// The applied rules in order
+    pub steps: Vec<RewriteStep>,
+    /// The result of rewriting
+    pub result: Result<Expression, RewriteError>,
+}
+
+impl fmt::Display for Derivation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!{ writeln!(f, "{}", self.start) };
+
+        // replay the steps to show the whole expression after each one
+        let mut current = Some(self.start.clone());
+        for s in self.steps.iter() {
+            current = current.and_then(|e| e.replace_at(&s.position, s.after.clone()));
+            match current {
+                Some(ref e) => {
+                    try!{ write!(f, "  = {}  by rule {} {} at {:?}", e, s.rule,
+                                 s.pattern, s.position) };
+                    try!{ write_bindings(f, &s.bindings) };
+                }
+                // the position vanished, e.g. because a sequence was spliced
+                None => try!{ write!(f, "  {}", s) }
+            }
+            try!{ writeln!(f, "") };
+        }
+
+        // the error counts passes for `trace_repeated_rules`, so the applied
+        // rules are counted instead
+        match self.result {
+            Ok(_) => Ok(()),
+            Err(_) => writeln!(f, "  gave up after {} steps", self.steps.len())
+        }
+    }
+}
@@ -0,0 +1,429 @@
+// Copyright (C) 2015  Jonas Pollok <jonas.p@gmail.com>
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::usize;
+
+use binding::{Bind, Binding};
+use expression::{self, Expression, RewriteError};
+use matching::Match;
+use rule::{self, RuleSet};
+use trace::{Derivation, RewriteStep};
+
+/// The `Traversal` determines where and in which order rules are applied
+/// when rewriting an expression with `Expression::replace_with`.
+///
+/// `BottomUp`, `TopDown` and `ParallelOutermost` visit every subexpression
+/// once, `OnceAnywhere` applies a single rule, while `Innermost` and
+/// `Outermost` rewrite until no rule applies anymore.
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[unstable(feature = "ers1")]
+pub enum Traversal {
+    /// Rewrites the arguments of an expression to normal form before the
+    /// expression itself (applicative order, strict semantics)
+    Innermost,
+    /// Rewrites the leftmost outermost subexpression until no rule applies
+    /// anymore (normal order, lazy semantics)
+    Outermost,
+    /// Applies the rules once at every subexpression, starting at the
+    /// leaves
+    BottomUp,
+    /// Applies the rules once at every subexpression, starting at the root
+    /// and continuing with the subexpressions of the result
+    TopDown,
+    /// Applies the rules to all outermost matching subexpressions, their
+    /// subexpressions are not visited
+    ParallelOutermost,
+    /// Applies the rules to the leftmost outermost matching subexpression
+    /// only
+    OnceAnywhere,
+}
+
+// The rules to rewrite with, either a rule set or a single pattern and
+// template borrowed from the caller.
+#[derive(Clone, Copy)]
+pub enum Rules<'r> {
+    Set(&'r RuleSet),
+    Pattern(&'r Expression, &'r Expression),
+}
+
+impl<'r> Rules<'r> {
+    // Returns the index of the first applying rule together with the result
+    // and the bindings of the match.
+    fn apply<'a>(&self, e: &'a Expression)
+                 -> Option<(usize, Expression, HashMap<String, Binding<'a>>)> {
+        match *self {
+            Rules::Set(rules) => rule::apply_traced(rules, e),
+            Rules::Pattern(p, t) => e.match_pattern(p).map(|bs| (0, t.clone().bind(&bs), bs)),
+        }
+    }
+
+    fn pattern(&self, i: usize) -> &'r Expression {
+        match *self {
+            Rules::Set(rules) => &rules.rules()[i].lhs,
+            Rules::Pattern(p, _) => p,
+        }
+    }
+}
+
+// Counts the applied rules and stops rewriting once the limit is reached.
+// If tracing, the applied rules are recorded together with the position of
+// the current subexpression.
+struct Rewriter<'r> {
+    rules: Rules<'r>,
+    steps: usize,
+    limit: usize,
+    exhausted: bool,
+    path: Vec<usize>,
+    trace: Option<Vec<RewriteStep>>,
+}
+
+impl<'r> Rewriter<'r> {
+    fn new(rules: Rules<'r>, limit: usize, trace: Option<Vec<RewriteStep>>) -> Rewriter<'r> {
+        Rewriter {
+            rules: rules,
+            steps: 0,
+            limit: limit,
+            exhausted: false,
+            path: Vec::new(),
+            trace: trace,
+        }
+    }
+
+    fn apply(&mut self, e: &Expression) -> Option<Expression> {
+        if self.exhausted {
+            return None;
+        }
+
+        let (i, res, bs) = match self.rules.apply(e) {
+            Some(r) => r,
+            None => return None
+        };
+        if self.steps == self.limit {
+            self.exhausted = true;
+            return None;
+        }
+        self.steps += 1;
+
+        if let Some(ref mut trace) = self.trace {
+            trace.push(RewriteStep {
+                rule: i,
+                pattern: self.rules.pattern(i).clone(),
+                position: self.path.clone(),
+                bindings: bs.into_iter().map(|(name, b)| (name, into_expression(b))).collect(),
+                before: e.clone(),
+                after: res.clone(),
+            });
+        }
+        Some(res)
+    }
+
+    fn children<F>(&mut self, e: Expression, mut f: F) -> Expression
+        where F: FnMut(&mut Rewriter<'r>, Expression) -> Expression {
+        match e {
+            Expression::List(es) => {
+                let mut v: Vec<Expression> = Vec::new();
+                for e in es {
+                    // the position of e once the preceding results are spliced
+                    self.path.push(v.len());
+                    let res = f(self, e);
+                    self.path.pop();
+                    expression::splice(&mut v, res);
+                }
+                Expression::List(v)
+            }
+            _ => e
+        }
+    }
+
+    fn innermost(&mut self, e: Expression) -> Expression {
+        let e = self.children(e, |r, e| r.innermost(e));
+        match self.apply(&e) {
+            Some(res) => self.innermost(res),
+            None => e
+        }
+    }
+
+    fn outermost(&mut self, e: Expression) -> Expression {
+        let mut e = e;
+        loop {
+            let (res, replaced) = self.once(e);
+            if !replaced {
+                return res;
+            }
+            e = res;
+        }
+    }
+
+    fn bottom_up(&mut self, e: Expression) -> Expression {
+        let e = self.children(e, |r, e| r.bottom_up(e));
+        match self.apply(&e) {
+            Some(res) => res,
+            None => e
+        }
+    }
+
+    fn top_down(&mut self, e: Expression) -> Expression {
+        let e = match self.apply(&e) {
+            Some(res) => res,
+            None => e
+        };
+        self.children(e, |r, e| r.top_down(e))
+    }
+
+    fn parallel_outermost(&mut self, e: Expression) -> Expression {
+        match self.apply(&e) {
+            Some(res) => res,
+            None => self.children(e, |r, e| r.parallel_outermost(e))
+        }
+    }
+
+    fn once(&mut self, e: Expression) -> (Expression, bool) {
+        if let Some(res) = self.apply(&e) {
+            return (res, true);
+        }
+
+        let mut replaced = false;
+        let e = self.children(e, |r, e| {
+            if replaced {
+                return e;
+            }
+            let (res, rep) = r.once(e);
+            replaced = rep;
+            res
+        });
+        (e, replaced)
+    }
+}
+
+fn into_expression(b: Binding) -> Expression {
+    match b {
+        Binding::Expression(e) => e.into_owned(),
+        Binding::Sequence(es) => Expression::Sequence(es.into_owned()),
+    }
+}
+
+// Rewrites the expression according to the traversal applying at most
+// `limit` rules.
+pub fn rewrite(e: &Expression, rules: Rules, traversal: Traversal,
+               limit: usize) -> Result<Expression, RewriteError> {
+    run(e, rules, traversal, limit, None).0
+}
+
+// Same as `rewrite` but records the applied rules.
+pub fn trace(e: &Expression, rules: Rules, traversal: Traversal,
+             limit: usize) -> Derivation {
+    let (res, steps) = run(e, rules, traversal, limit, Some(Vec::new()));
+    Derivation {
+        start: e.clone(),
+        steps: steps.unwrap_or_default(),
+        result: res,
+    }
+}
+
+fn run(e: &Expression, rules: Rules, traversal: Traversal, limit: usize,
+       trace: Option<Vec<RewriteStep>>) -> (Result<Expression, RewriteError>, Option<Vec<RewriteStep>>) {
+    let mut r = Rewriter::new(rules, limit, trace);
+
+    let e = e.clone();
+    let res = match traversal {
+        Traversal::Innermost => r.innermost(e),
+        Traversal::Outermost => r.outermost(e),
+        Traversal::BottomUp => r.bottom_up(e),
+        Traversal::TopDown => r.top_down(e),
+        Traversal::ParallelOutermost => r.parallel_outermost(e),
+        Traversal::OnceAnywhere => r.once(e).0,
+    };
+
+    let res = if r.exhausted {
+        Err(RewriteError { expression: res, steps: r.steps })
+    } else {
+        Ok(res)
+    };
+    (res, r.trace)
+}
+
+// Rewrites the expression with `ParallelOutermost` passes until no rule
+// applies anymore. Unlike the other traversals `limit` is the number of
+// passes.
+pub fn rewrite_repeated(e: &Expression, rules: Rules,
+                        limit: usize) -> Result<Expression, RewriteError> {
+    run_repeated(e, rules, limit, None).0
+}
+
+// Same as `rewrite_repeated` but records the applied rules.
+pub fn trace_repeated(e: &Expression, rules: Rules, limit: usize) -> Derivation {
+    let (res, steps) = run_repeated(e, rules, limit, Some(Vec::new()));
+    Derivation {
+        start: e.clone(),
+        steps: steps.unwrap_or_default(),
+        result: res,
+    }
+}
+
+fn run_repeated(e: &Expression, rules: Rules, limit: usize, trace: Option<Vec<RewriteStep>>)
+                -> (Result<Expression, RewriteError>, Option<Vec<RewriteStep>>) {
+    let mut r = Rewriter::new(rules, usize::MAX, trace);
+    let mut expr = e.clone();
+    let mut passes = 0;
+    loop {
+        let (steps, recorded) = (r.steps, r.trace.as_ref().map_or(0, |t| t.len()));
+        let res = r.parallel_outermost(expr.clone());
+        if r.steps == steps {
+            return (Ok(res), r.trace);
+        }
+        if passes == limit {
+            // the steps of the pass which is not performed are dropped
+            if let Some(ref mut t) = r.trace {
+                t.truncate(recorded);
+            }
+            return (Err(RewriteError { expression: expr, steps: passes }), r.trace);
+        }
+        expr = res;
+        passes += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use expression::Expression;
+    use rule::RuleSet;
+    use super::Traversal;
+
+    fn rewrite(e: &str, rules: &str, traversal: Traversal) -> String {
+        let e = e.parse::<Expression>().unwrap();
+        let rules = rules.parse::<RuleSet>().unwrap();
+        format!("{:?}", e.replace_with(&rules, traversal, 100).unwrap())
+    }
+
+    #[test]
+    fn single_pass() {
+        let rules = "(Rule (f x_) (g x))";
+
+        assert_eq!(rewrite("(f (f a))", rules, Traversal::ParallelOutermost), "(g (f a))");
+        assert_eq!(rewrite("(f (f a))", rules, Traversal::TopDown), "(g (g a))");
+        assert_eq!(rewrite("(f (f a))", rules, Traversal::BottomUp), "(g (g a))");
+        assert_eq!(rewrite("(f (f a))", rules, Traversal::OnceAnywhere), "(g (f a))");
+        assert_eq!(rewrite("(h (f a) (f b))", rules, Traversal::OnceAnywhere), "(h (g a) (f b))");
+
+        let rules = "((Rule (f (g x_)) (done x)) (Rule (g x_) (k x)))";
+
+        assert_eq!(rewrite("(f (g a))", rules, Traversal::TopDown), "(done a)");
+        assert_eq!(rewrite("(f (g a))", rules, Traversal::BottomUp), "(f (k a))");
+    }
+
+    #[test]
+    fn normal_form() {
+        // `(first x y)` only needs its first argument
+        let rules = "((Rule (first x_ y_) x) (Rule loop (succ loop)))";
+
+        assert_eq!(rewrite("(first a loop)", rules, Traversal::Outermost), "a");
+
+        let e = "(first a loop)".parse::<Expression>().unwrap();
+        let rules = rules.parse::<RuleSet>().unwrap();
+        let err = e.replace_with(&rules, Traversal::Innermost, 3).unwrap_err();
+        assert_eq!(err.steps, 3);
+        assert_eq!(format!("{:?}", err.expression), "(first a (succ (succ (succ loop))))");
+    }
+
+    #[test]
+    fn trace() {
+        let e = "(Plus (s (s 0)) (Plus (s 0) 0))".parse::<Expression>().unwrap();
+        let rules = "((Rule (Plus 0 x_) x) (Rule (Plus (s x_) y_) (s (Plus x y))))"
+            .parse::<RuleSet>().unwrap();
+
+        let d = e.trace_with(&rules, Traversal::Innermost, 100);
+        let steps: Vec<String> = d.steps.iter().map(|s| format!("{}", s)).collect();
+        assert_eq!(steps, vec![
+            "(Plus (s 0) 0) -> (s (Plus 0 0)) by rule 1 (Plus (s x_) y_) at [2] with x = 0, y = 0",
+            "(Plus 0 0) -> 0 by rule 0 (Plus 0 x_) at [2, 1] with x = 0",
+            "(Plus (s (s 0)) (s 0)) -> (s (Plus (s 0) (s 0))) by rule 1 (Plus (s x_) y_) at [] \
+             with x = (s 0), y = (s 0)",
+            "(Plus (s 0) (s 0)) -> (s (Plus 0 (s 0))) by rule 1 (Plus (s x_) y_) at [1] \
+             with x = 0, y = (s 0)",
+            "(Plus 0 (s 0)) -> (s 0) by rule 0 (Plus 0 x_) at [1, 1] with x = (s 0)",
+        ]);
+        assert_eq!(format!("{:?}", d.result.unwrap()), "(s (s (s 0)))");
+        assert_eq!(d.steps[1].position, vec![2, 1]);
+        assert_eq!(format!("{:?}", d.steps[1].bindings["x"]), "0");
+
+        let rules = "((Rule (f x_) (Sequence x x)) (Rule a b))".parse::<RuleSet>().unwrap();
+        let e = "(g (f a) a)".parse::<Expression>().unwrap();
+        let d = e.trace_with(&rules, Traversal::TopDown, 100);
+        assert_eq!(format!("{}", d), "\
+(g (f a) a)
+  = (g a a a)  by rule 0 (f x_) at [1] with x = a
+  = (g a a b)  by rule 1 a at [3]
+");
+
+        let rules = "(Rule loop (succ loop))".parse::<RuleSet>().unwrap();
+        let e = "loop".parse::<Expression>().unwrap();
+        let d = e.trace_repeated_rules(&rules, 2);
+        assert_eq!(format!("{}", d), "\
+loop
+  = (succ loop)  by rule 0 loop at []
+  = (succ (succ loop))  by rule 0 loop at [1]
+  gave up after 2 steps
+");
+        assert_eq!(format!("{:?}", d.result.unwrap_err().expression), "(succ (succ loop))");
+
+        // a single pass applies both rules
+        let e = "(h loop loop)".parse::<Expression>().unwrap();
+        let d = e.trace_repeated_rules(&rules, 1);
+        assert_eq!(d.result.as_ref().unwrap_err().steps, 1);
+        assert_eq!(format!("{}", d), "\
+(h loop loop)
+  = (h (succ loop) loop)  by rule 0 loop at [1]
+  = (h (succ loop) (succ loop))  by rule 0 loop at [2]
+  gave up after 2 steps
+");
+
+        let e = "loop".parse::<Expression>().unwrap();
+        let d = e.trace_with(&rules, Traversal::Innermost, 2);
+        assert_eq!(d.steps.len(), 2);
+        assert_eq!(format!("{}", d), "\
+loop
+  = (succ loop)  by rule 0 loop at []
+  = (succ (succ loop))  by rule 0 loop at [1]
+  gave up after 2 steps
+");
+    }
+
+    #[test]
+    fn trace_repeated() {
+        let rules = "((Rule (x a_) (y a)) (Rule (z a_) (w a)))".parse::<RuleSet>().unwrap();
+        let e = "(f (x (z (x r))) (z s))".parse::<Expression>().unwrap();
+
+        let d = e.trace_repeated_rules(&rules, 100);
+        assert_eq!(format!("{}", d), "\
+(f (x (z (x r))) (z s))
+  = (f (y (z (x r))) (z s))  by rule 0 (x a_) at [1] with a = (z (x r))
+  = (f (y (z (x r))) (w s))  by rule 1 (z a_) at [2] with a = s
+  = (f (y (w (x r))) (w s))  by rule 1 (z a_) at [1, 1] with a = (x r)
+  = (f (y (w (y r))) (w s))  by rule 0 (x a_) at [1, 1, 1] with a = r
+");
+        assert_eq!(d.result.unwrap(), e.replace_repeated_rules(&rules, 100).unwrap());
+    }
+
+    #[test]
+    fn innermost() {
+        let rules = "((Rule (Plus 0 x_) x) (Rule (Plus (s x_) y_) (s (Plus x y))))";
+
+        assert_eq!(rewrite("(Plus (s (s 0)) (Plus (s 0) 0))", rules, Traversal::Innermost),
+                   "(s (s (s 0)))");
+        assert_eq!(rewrite("(Plus (s (s 0)) (Plus (s 0) 0))", rules, Traversal::Outermost),
+                   "(s (s (s 0)))");
+    }
+}